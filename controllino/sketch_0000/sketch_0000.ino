@@ -11,6 +11,75 @@ EthernetServer server(80);
 // Track pin states
 bool pinStates[3] = { false, false, false }; // DO0, DO1, DO2
 
+// Auto-off: a pin turned on goes off by itself after its ttl unless the
+// director sends `state=on` again (heartbeat) before it expires
+const unsigned long DEFAULT_TTL_MS = 30000;
+const unsigned long MAX_TTL_MS = 60000;
+unsigned long onSince[3] = { 0, 0, 0 };
+unsigned long ttls[3] = { 0, 0, 0 };
+
+// Digital inputs (e-stop, door switch, presence sensor), read by the
+// director with `GET /inputs`. A level counts once stable for DEBOUNCE_MS
+const unsigned long DEBOUNCE_MS = 30;
+bool inputStates[4] = { false, false, false, false }; // A0-A3 as i0-i3
+bool inputReadings[4] = { false, false, false, false };
+unsigned long readingSince[4] = { 0, 0, 0, 0 };
+
+int pinFor(int index) {
+    if (index == 0) return CONTROLLINO_DO0;
+    if (index == 1) return CONTROLLINO_DO1;
+    return CONTROLLINO_DO2;
+}
+
+int inputPinFor(int index) {
+    if (index == 0) return CONTROLLINO_A0;
+    if (index == 1) return CONTROLLINO_A1;
+    if (index == 2) return CONTROLLINO_A2;
+    return CONTROLLINO_A3;
+}
+
+void readInputs() {
+    unsigned long now = millis();
+    for (int i = 0; i < 4; i++) {
+        bool reading = digitalRead(inputPinFor(i)) == HIGH;
+        if (reading != inputReadings[i]) {
+            inputReadings[i] = reading;
+            readingSince[i] = now;
+        } else if (reading != inputStates[i] && now - readingSince[i] >= DEBOUNCE_MS) {
+            inputStates[i] = reading;
+            Serial.print("Input i");
+            Serial.print(i);
+            Serial.println(reading ? " HIGH" : " LOW");
+        }
+    }
+}
+
+// Prints `{"<prefix>0": true, ...}`
+void printStates(EthernetClient &client, const char *prefix, bool *states, int count) {
+    client.print("{");
+    for (int i = 0; i < count; i++) {
+        client.print("\"");
+        client.print(prefix);
+        client.print(i);
+        client.print("\": ");
+        client.print(states[i] ? "true" : "false");
+        if (i < count - 1) client.print(", ");
+    }
+    client.println("}");
+}
+
+void expirePins() {
+    unsigned long now = millis();
+    for (int i = 0; i < 3; i++) {
+        if (pinStates[i] && now - onSince[i] >= ttls[i]) {
+            pinStates[i] = false;
+            digitalWrite(pinFor(i), LOW);
+            Serial.print("Auto-off s");
+            Serial.println(i);
+        }
+    }
+}
+
 void setup() {
     Serial.begin(9600);
     Ethernet.begin(mac, ip);
@@ -24,11 +93,17 @@ void setup() {
     digitalWrite(CONTROLLINO_DO1, LOW);
     digitalWrite(CONTROLLINO_DO2, LOW);
 
+    for (int i = 0; i < 4; i++) {
+        pinMode(inputPinFor(i), INPUT);
+    }
+
     Serial.print("Server is running at: ");
     Serial.println(ip);
 }
 
 void loop() {
+    expirePins();
+    readInputs();
     EthernetClient client = server.available();
     if (client) {
         Serial.println("New Client Connected");
@@ -50,16 +125,20 @@ void loop() {
 
                     if (pinIndex != -1) {
                         if (request.indexOf("state=on") != -1) {
+                            unsigned long ttl = DEFAULT_TTL_MS;
+                            int ttlAt = request.indexOf("ttl=");
+                            if (ttlAt != -1) {
+                                ttl = request.substring(ttlAt + 4).toInt();
+                            }
+                            if (ttl == 0 || ttl > MAX_TTL_MS) ttl = MAX_TTL_MS;
                             pinStates[pinIndex] = true;
+                            onSince[pinIndex] = millis();
+                            ttls[pinIndex] = ttl;
                         } else if (request.indexOf("state=off") != -1) {
                             pinStates[pinIndex] = false;
                         }
                         // Set the actual pin
-                        int pin;
-                        if (pinIndex == 0) pin = CONTROLLINO_DO0;
-                        else if (pinIndex == 1) pin = CONTROLLINO_DO1;
-                        else pin = CONTROLLINO_DO2;
-                        digitalWrite(pin, pinStates[pinIndex] ? HIGH : LOW);
+                        digitalWrite(pinFor(pinIndex), pinStates[pinIndex] ? HIGH : LOW);
                     }
 
                     // Send response
@@ -68,15 +147,11 @@ void loop() {
                     client.println("Connection: close");
                     client.println();
 
-                    client.print("{");
-                    for (int i = 0; i < 3; i++) {
-                        client.print("\"s");
-                        client.print(i);
-                        client.print("\": ");
-                        client.print(pinStates[i] ? "true" : "false");
-                        if (i < 2) client.print(", ");
+                    if (request.indexOf("GET /inputs") != -1) {
+                        printStates(client, "i", inputStates, 4);
+                    } else {
+                        printStates(client, "s", pinStates, 3);
                     }
-                    client.println("}");
 
                     break;
                 }
@@ -0,0 +1,334 @@
+use chrono::{DateTime, Local};
+use rosc::{decoder, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Notify;
+
+use crate::journal::{self, Origin};
+use crate::lights::Light;
+use crate::robots::Robot;
+use crate::sparklings::Sparkling;
+use crate::utils;
+
+/// How often a latch set by another process is looked for
+const POLL_MS: u64 = 500;
+/// How often a confirmation of the motor stop is looked for
+const STOPPED_POLL_MS: u64 = 100;
+
+/// Where an emergency stop or reset came from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+  Cli,
+  Http,
+  Osc,
+  Keyboard,
+  /// A Controllino input, e.g. a mushroom button
+  Input,
+  /// The latch file could not be read
+  Unknown,
+}
+
+/// A latched emergency stop, kept in a file so every director process sees
+/// it and it survives a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Latch {
+  pub since: DateTime<Local>,
+  pub source: Source,
+  pub reason: Option<String>,
+  /// Every motor acknowledged its stop
+  #[serde(default)]
+  pub stopped: bool,
+}
+
+/// `estop` section of the show file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EstopConfig {
+  /// Level the lights go to, full so the room stays lit
+  pub light_level: u16,
+  /// Where `/estop` is received, none to disable
+  pub osc_address: Option<String>,
+  /// Where `POST /estop` is served, none to disable
+  pub http_address: Option<String>,
+}
+
+impl Default for EstopConfig {
+  fn default() -> Self {
+    EstopConfig {
+      light_level: 10000,
+      osc_address: Some(String::from("0.0.0.0:6667")),
+      http_address: Some(String::from("0.0.0.0:9465")),
+    }
+  }
+}
+
+static CHANGED: Notify = Notify::const_new();
+
+fn latch_path() -> PathBuf {
+  utils::get_home_dir().join("triennale-25/estop.json")
+}
+
+/// The emergency stop in force, if any. Only a missing latch file releases
+/// it, one that cannot be read or parsed counts as latched.
+pub fn latched() -> Option<Latch> {
+  let content = match fs::read_to_string(latch_path()) {
+    Ok(content) => content,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+    Err(e) => return Some(unreadable(e.into())),
+  };
+  match serde_json::from_str(&content) {
+    Ok(latch) => Some(latch),
+    Err(e) => Some(unreadable(e.into())),
+  }
+}
+
+fn unreadable(error: anyhow::Error) -> Latch {
+  tracing::error!(error = %error, "Unreadable emergency stop latch");
+  Latch {
+    since: Local::now(),
+    source: Source::Unknown,
+    reason: Some(format!("Unreadable latch: {}", error)),
+    stopped: false,
+  }
+}
+
+/// Latches the emergency stop. The hardware is halted by whoever drives it,
+/// see `halt`. A stop already latched is kept as it is.
+pub fn trigger(source: Source, reason: Option<String>) -> anyhow::Result<()> {
+  if let Some(latch) = latched() {
+    tracing::warn!(since = %latch.since, "Emergency stop already latched");
+    CHANGED.notify_waiters();
+    return Ok(());
+  }
+  tracing::error!(?source, reason, "EMERGENCY STOP");
+  let latch = Latch {
+    since: Local::now(),
+    source,
+    reason,
+    stopped: false,
+  };
+  let result = write_latch(&latch);
+  journal::record_result(
+    "director",
+    "estop",
+    json!({ "source": source, "reason": latch.reason }),
+    &result,
+  );
+  CHANGED.notify_waiters();
+  result
+}
+
+/// Releases the emergency stop, the robots are homed before the show goes
+/// on.
+pub fn reset(source: Source) -> anyhow::Result<()> {
+  if latched().is_none() {
+    anyhow::bail!("Emergency stop not latched");
+  }
+  tracing::warn!(?source, "Emergency stop reset");
+  let result = fs::remove_file(latch_path()).map_err(anyhow::Error::from);
+  journal::record_result(
+    "director",
+    "estop_reset",
+    json!({ "source": source }),
+    &result,
+  );
+  CHANGED.notify_waiters();
+  result
+}
+
+fn write_latch(latch: &Latch) -> anyhow::Result<()> {
+  let path = latch_path();
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  // Renamed into place so a reader never sees a half-written latch
+  let tmp = path.with_extension("json.tmp");
+  fs::write(&tmp, serde_json::to_string_pretty(latch)?)?;
+  fs::rename(tmp, path)?;
+  Ok(())
+}
+
+/// Records that every motor acknowledged its stop.
+pub fn confirm_stopped() -> anyhow::Result<()> {
+  match latched() {
+    Some(latch) => write_latch(&Latch {
+      stopped: true,
+      ..latch
+    }),
+    None => Ok(()),
+  }
+}
+
+/// Waits up to `timeout_ms` for the motor stop to be confirmed, e.g. by the
+/// installation holding the motor bus.
+pub async fn wait_stopped(timeout_ms: u64) -> bool {
+  let started = std::time::Instant::now();
+  loop {
+    if latched().is_some_and(|latch| latch.stopped) {
+      return true;
+    }
+    if started.elapsed().as_millis() as u64 >= timeout_ms {
+      return false;
+    }
+    utils::sleep_silent(STOPPED_POLL_MS).await;
+  }
+}
+
+/// Waits until the emergency stop is latched, or released with `false`.
+pub async fn wait_until(latch: bool) {
+  while latched().is_some() != latch {
+    tokio::select! {
+      _ = CHANGED.notified() => {}
+      _ = utils::sleep_silent(POLL_MS) => {}
+    }
+  }
+}
+
+/// Stops every motor and faults its robot, cuts the relays and sets the
+/// lights to the safe level. Fails when a motor did not acknowledge its
+/// stop, the relays and lights are handled all the same.
+pub async fn halt(
+  robots: &[Arc<Robot>],
+  lights: &[Arc<Light>],
+  sparklings: &[Arc<Sparkling>],
+) -> anyhow::Result<()> {
+  let stopped = stop_motors(robots).await;
+  futures::future::join_all(sparklings.iter().map(|s| s.turn_off())).await;
+  let level = crate::show::get().estop.light_level;
+  let lights = lights.to_vec();
+  tokio::task::spawn_blocking(move || {
+    for light in &lights {
+      light.dim(level);
+    }
+  })
+  .await
+  .expect("Safe light level panicked");
+  stopped
+}
+
+/// Stops every motor where it is and faults its robot. Motors are stopped
+/// rather than disabled, so they keep holding the cables.
+pub async fn stop_motors(robots: &[Arc<Robot>]) -> anyhow::Result<()> {
+  let mut failed = vec![];
+  for robot in robots {
+    if let Err(e) = robot.cancel_move().await {
+      tracing::error!(robot = robot.name(), error = %e, "Cannot stop motor");
+      failed.push(format!("{} ({})", robot.name(), e));
+    }
+    robot.fault("Emergency stop").await;
+  }
+  if !failed.is_empty() {
+    anyhow::bail!("Motors not stopped: {}", failed.join(", "));
+  }
+  Ok(())
+}
+
+/// Answers `/estop [<reason>]` until the process exits. A reset is only
+/// taken from the command line or the console, `/estop/reset` is refused.
+pub async fn listen_osc(address: &str) -> anyhow::Result<()> {
+  let socket = UdpSocket::bind(address).await?;
+  tracing::info!(address, "Listening for /estop");
+  let mut buf = [0; rosc::decoder::MTU];
+  loop {
+    let (size, _) = socket.recv_from(&mut buf).await?;
+    match decoder::decode_udp(&buf[..size]) {
+      Ok((_, packet)) => handle_packet(packet),
+      Err(e) => tracing::debug!(error = ?e, "Invalid OSC packet"),
+    }
+  }
+}
+
+fn handle_packet(packet: OscPacket) {
+  match packet {
+    OscPacket::Message(msg) => {
+      let result = match msg.addr.as_str() {
+        "/estop" => {
+          let reason = match msg.args.first() {
+            Some(OscType::String(reason)) => Some(reason.clone()),
+            _ => None,
+          };
+          trigger(Source::Osc, reason)
+        }
+        "/estop/reset" => {
+          tracing::warn!("Emergency stop reset refused, use the console");
+          Ok(())
+        }
+        _ => Ok(()),
+      };
+      if let Err(e) = result {
+        tracing::error!(error = %e, "OSC emergency stop failed");
+      }
+    }
+    OscPacket::Bundle(bundle) => {
+      bundle.content.into_iter().for_each(handle_packet)
+    }
+  }
+}
+
+/// Serves `POST /estop` until the process exits, on its own listener so it
+/// does not depend on the metrics. `POST /estop/reset` is refused, a reset
+/// is only taken from the command line or the console.
+pub async fn serve_http(address: &str) -> anyhow::Result<()> {
+  let listener = TcpListener::bind(address).await?;
+  tracing::info!(address, "Serving /estop");
+  loop {
+    let (stream, _) = listener.accept().await?;
+    tokio::spawn(journal::scope(Origin::Api, async move {
+      if let Err(e) = respond(stream).await {
+        tracing::debug!(error = %e, "Emergency stop request failed");
+      }
+    }));
+  }
+}
+
+async fn respond(mut stream: TcpStream) -> anyhow::Result<()> {
+  let (method, path) = utils::read_http_request(&mut stream).await?;
+  let result = match (method.as_str(), path.as_str()) {
+    ("POST", "/estop") => trigger(Source::Http, None),
+    ("POST", "/estop/reset") => {
+      return utils::write_http_response(
+        &mut stream,
+        "403 Forbidden",
+        "text/plain",
+        "Reset from the console\n",
+      )
+      .await
+    }
+    _ => {
+      return utils::write_http_response(
+        &mut stream,
+        "404 Not Found",
+        "text/plain",
+        "Not found\n",
+      )
+      .await
+    }
+  };
+  let (status, body) = match result {
+    Ok(()) => ("200 OK", String::from("OK\n")),
+    Err(e) => ("409 Conflict", format!("{}\n", e)),
+  };
+  utils::write_http_response(&mut stream, status, "text/plain", &body).await
+}
+
+/// Console shortcuts of the installation: `e` then Enter stops, `reset`
+/// releases.
+pub async fn listen_keyboard() {
+  let mut lines = BufReader::new(tokio::io::stdin()).lines();
+  while let Ok(Some(line)) = lines.next_line().await {
+    let result = match line.trim() {
+      "e" | "estop" => trigger(Source::Keyboard, None),
+      "reset" => reset(Source::Keyboard),
+      _ => continue,
+    };
+    if let Err(e) = result {
+      tracing::error!(error = %e, "Keyboard emergency stop failed");
+    }
+  }
+}
@@ -0,0 +1,83 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use std::future::Future;
+use std::sync::Mutex;
+
+use crate::utils;
+
+/// How a group operation is spread over the devices of a manager, e.g.
+/// `{ "type": "wave", "interval_ms": 300 }`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Stagger {
+  /// All devices at once.
+  Simultaneous,
+  /// One device after the other, each waiting for the previous one to be
+  /// done, then `interval_ms`.
+  Sequential { interval_ms: u64 },
+  /// Devices start `interval_ms` apart in a random order, without waiting
+  /// for each other.
+  Random { interval_ms: u64 },
+  /// Devices start `interval_ms` apart in order, without waiting for each
+  /// other.
+  Wave { interval_ms: u64 },
+}
+
+/// `fanout` section of the show file.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct FanoutConfig {
+  pub lights: Stagger,
+  pub sparklings: Stagger,
+}
+
+impl Default for FanoutConfig {
+  fn default() -> Self {
+    FanoutConfig {
+      lights: Stagger::Simultaneous,
+      sparklings: Stagger::Wave { interval_ms: 1000 },
+    }
+  }
+}
+
+/// Runs `op` on every item following `stagger`, returns once all are done.
+/// `rng` orders the random stagger.
+pub async fn run<T, F, Fut>(
+  stagger: Stagger,
+  items: Vec<T>,
+  rng: &Mutex<StdRng>,
+  op: F,
+) where
+  F: Fn(T) -> Fut,
+  Fut: Future<Output = ()>,
+{
+  let (interval_ms, mut items) = match stagger {
+    Stagger::Simultaneous => {
+      futures::future::join_all(items.into_iter().map(op)).await;
+      return;
+    }
+    Stagger::Sequential { interval_ms } => {
+      let count = items.len();
+      for (index, item) in items.into_iter().enumerate() {
+        op(item).await;
+        if index + 1 < count {
+          utils::sleep_silent(interval_ms).await;
+        }
+      }
+      return;
+    }
+    Stagger::Random { interval_ms } => {
+      let mut items = items;
+      items.shuffle(&mut *rng.lock().unwrap());
+      (interval_ms, items)
+    }
+    Stagger::Wave { interval_ms } => (interval_ms, items),
+  };
+  let op = &op;
+  let staggered = items.drain(..).enumerate().map(|(index, item)| async move {
+    utils::sleep_silent(interval_ms * index as u64).await;
+    op(item).await;
+  });
+  futures::future::join_all(staggered).await;
+}
@@ -0,0 +1,233 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+use crate::config::{self, ConfigParam};
+use crate::estop::{self, Source};
+use crate::installation::Phase;
+use crate::metrics;
+use crate::presence;
+use crate::sparklings::relay::{RelayController, RelayStates};
+use crate::sparklings::SPARKLING_SERVICE_IP;
+use crate::utils::{self, print_dry_run};
+
+/// Digital inputs of the Controllino
+const PINS: [&str; 4] = ["i0", "i1", "i2", "i3"];
+
+/// `inputs` section of the show file: what the director does when a
+/// Controllino input becomes active, by pin.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct InputsConfig {
+  pub poll_ms: u64,
+  /// How long a poll waits for the Controllino, it is not retried
+  pub timeout_ms: u64,
+  /// How long polls may fail before a `halt` input counts as active
+  pub fail_safe_ms: u64,
+  pub pins: HashMap<String, Input>,
+}
+
+impl Default for InputsConfig {
+  fn default() -> Self {
+    InputsConfig {
+      poll_ms: 250,
+      timeout_ms: 200,
+      fail_safe_ms: 1000 * 2,
+      pins: HashMap::new(),
+    }
+  }
+}
+
+impl InputsConfig {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    for pin in self.pins.keys() {
+      if !PINS.contains(&pin.as_str()) {
+        anyhow::bail!("Unknown input {}, use {}", pin, PINS.join(", "));
+      }
+    }
+    if self.poll_ms == 0 {
+      anyhow::bail!("poll_ms must be over 0");
+    }
+    if self.timeout_ms == 0 {
+      anyhow::bail!("timeout_ms must be over 0");
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Input {
+  /// Name in the logs and metrics, the pin by default
+  pub name: Option<String>,
+  #[serde(flatten)]
+  pub action: Action,
+  /// Active when low, e.g. a normally closed e-stop or door contact
+  #[serde(default)]
+  pub active_low: bool,
+}
+
+impl Input {
+  pub fn name<'a>(&'a self, pin: &'a str) -> &'a str {
+    self.name.as_deref().unwrap_or(pin)
+  }
+
+  pub fn is_active(&self, level: bool) -> bool {
+    level != self.active_low
+  }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action {
+  /// Latches the emergency stop, again if reset while still active
+  Halt,
+  /// Stops the show while active, it starts again from homing the robots
+  Pause,
+  /// Cuts the current phase short for `phase`
+  Scene { phase: Phase },
+  /// Somebody is around while active, see `presence`
+  Presence,
+}
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static PAUSE_CHANGED: Notify = Notify::const_new();
+static SCENE: Mutex<Option<Phase>> = Mutex::new(None);
+static SCENE_REQUESTED: Notify = Notify::const_new();
+
+/// Whether a pause input is active.
+pub fn paused() -> bool {
+  PAUSED.load(Ordering::SeqCst)
+}
+
+/// Waits until a pause input is active, or none is with `false`.
+pub async fn wait_paused(paused: bool) {
+  loop {
+    let changed = PAUSE_CHANGED.notified();
+    if self::paused() == paused {
+      return;
+    }
+    changed.await;
+  }
+}
+
+/// Cuts the running phase short for `phase`, ignored between phases.
+pub fn request_scene(phase: Phase) {
+  *SCENE.lock().unwrap() = Some(phase);
+  SCENE_REQUESTED.notify_waiters();
+}
+
+/// Waits for an input asking for a scene.
+pub async fn next_scene() -> Phase {
+  loop {
+    SCENE_REQUESTED.notified().await;
+    if let Some(phase) = SCENE.lock().unwrap().take() {
+      return phase;
+    }
+  }
+}
+
+/// Levels of the inputs as reported by the Controllino.
+pub async fn query() -> anyhow::Result<RelayStates> {
+  if config::get(ConfigParam::DRYRUN) {
+    print_dry_run("Invoked input query");
+    return Ok(RelayStates::new());
+  }
+  RelayController::new(SPARKLING_SERVICE_IP).inputs().await
+}
+
+/// Polls the inputs of the show file and acts on their changes. A poll that
+/// fails leaves every input as it was, but once polls failed for
+/// `fail_safe_ms` the emergency stop is latched if any input can halt.
+pub async fn monitor(config: &'static InputsConfig) {
+  if config.pins.is_empty() {
+    return;
+  }
+  if config::get(ConfigParam::DRYRUN) {
+    print_dry_run("Invoked input monitor");
+    return;
+  }
+  tracing::info!(inputs = config.pins.len(), "Starting input monitor...");
+  let controller = RelayController::new(SPARKLING_SERVICE_IP);
+  let mut active: HashMap<&str, bool> = HashMap::new();
+  let can_halt = config
+    .pins
+    .values()
+    .any(|input| matches!(input.action, Action::Halt));
+  let mut last_read = Instant::now();
+  loop {
+    match controller.poll_inputs(config.timeout_ms).await {
+      Ok(levels) => {
+        last_read = Instant::now();
+        for (pin, input) in &config.pins {
+          let Some(level) = levels.get(pin) else {
+            continue;
+          };
+          let now = input.is_active(*level);
+          let before = active.insert(pin, now).unwrap_or(false);
+          if now != before {
+            let name = input.name(pin);
+            tracing::info!(input = name, pin, active = now, "Input");
+            metrics::set(
+              &metrics::INPUT_ACTIVE,
+              &[("input", name)],
+              now as u8 as f64,
+            );
+          }
+          act(input.name(pin), input.action, now, before);
+        }
+        let paused = config.pins.iter().any(|(pin, input)| {
+          matches!(input.action, Action::Pause)
+            && active.get(pin.as_str()) == Some(&true)
+        });
+        if PAUSED.swap(paused, Ordering::SeqCst) != paused {
+          PAUSE_CHANGED.notify_waiters();
+        }
+      }
+      Err(e) => {
+        tracing::error!(error = %e, "Cannot read inputs");
+        let failing_ms = last_read.elapsed().as_millis() as u64;
+        if can_halt
+          && failing_ms >= config.fail_safe_ms
+          && estop::latched().is_none()
+        {
+          let reason = String::from("inputs unreachable");
+          if let Err(e) = estop::trigger(Source::Input, Some(reason)) {
+            tracing::error!(error = %e, "Cannot latch estop");
+          }
+        }
+      }
+    }
+    utils::sleep_silent(config.poll_ms).await;
+  }
+}
+
+fn act(name: &str, action: Action, active: bool, before: bool) {
+  match action {
+    Action::Halt => {
+      if active && estop::latched().is_none() {
+        if before {
+          tracing::warn!(input = name, "Emergency stop reset while active");
+        }
+        let result = estop::trigger(Source::Input, Some(name.to_string()));
+        if let Err(e) = result {
+          tracing::error!(input = name, error = %e, "Cannot latch estop");
+        }
+      }
+    }
+    Action::Pause => {}
+    Action::Scene { phase } => {
+      if active && !before {
+        tracing::info!(input = name, ?phase, "Scene requested");
+        request_scene(phase);
+      }
+    }
+    Action::Presence => {
+      if active != before {
+        presence::report(&format!("input {}", name), active);
+      }
+    }
+  }
+}
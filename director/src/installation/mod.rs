@@ -1,57 +1,388 @@
-use crate::{lights, robots, sparklings};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::journal::{self, Origin};
+use crate::presence::{self, Occupancy};
+use crate::schedule::{self, Period};
+use crate::{estop, inputs, lights, metrics, robots, show, sparklings, utils};
+
+/// How often the schedule is checked while closed or warming up
+const SCHEDULE_POLL_MS: u64 = 1000 * 30;
+/// How often motors that missed the emergency stop are stopped again
+const HALT_RETRY_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+  Buffering,
+  Scanning,
+  Syncing,
+}
+
+const CYCLE: [Phase; 4] = [
+  Phase::Buffering,
+  Phase::Scanning,
+  Phase::Buffering,
+  Phase::Syncing,
+];
 
 struct Director {
   robot_manager: robots::RobotManager,
   light_manager: lights::LightManager,
   sparkling_manager: sparklings::SparklingManager,
+  /// Phase to run first, instead of the one of the cycle
+  next_scene: Option<Phase>,
 }
 
 impl Director {
   async fn new() -> Self {
-    println!("Initializing director...");
+    tracing::info!("Initializing director...");
     let director: Director = Director {
       robot_manager: robots::RobotManager::new().await,
       light_manager: lights::LightManager::new().await,
       sparkling_manager: sparklings::SparklingManager::new().await,
+      next_scene: None,
     };
-    director.robot_manager.initialize_all().await;
-    println!("Director initialized");
+    tracing::info!("Director initialized");
     return director;
   }
+  async fn run(&mut self) {
+    if show::get().schedule.enabled {
+      self.start_scheduled().await;
+    } else {
+      self.start().await;
+    }
+  }
+  /// Halts the show while the emergency stop is latched, then starts it
+  /// again from homing the robots.
+  async fn halt(&mut self, latch: &estop::Latch) {
+    tracing::error!(
+      source = ?latch.source,
+      reason = latch.reason,
+      "Halted by emergency stop"
+    );
+    let started = metrics::enter_phase("FAULT");
+    self.light_manager.stop_cues();
+    let sparklings = self.sparkling_manager.sparklings().map(Arc::clone);
+    let robots = self.robot_manager.robots();
+    let mut result =
+      estop::halt(&robots, &self.light_manager.lights(), &sparklings).await;
+    // The motors are stopped until they all acknowledge, e.g. once a
+    // script holding the port is done
+    while let Err(e) = &result {
+      tracing::error!(error = %e, "Emergency stop not completed, retrying");
+      tokio::select! {
+        _ = estop::wait_until(false) => break,
+        _ = utils::sleep_silent(HALT_RETRY_MS) => {}
+      }
+      result = estop::stop_motors(&robots).await;
+    }
+    if result.is_ok() {
+      if let Err(e) = estop::confirm_stopped() {
+        tracing::error!(error = %e, "Cannot record the motor stop");
+      }
+    }
+    estop::wait_until(false).await;
+    metrics::exit_phase("FAULT", started);
+    tracing::info!("Emergency stop released, restarting");
+  }
+  /// Stops the robots where they are and the relays while an input pauses
+  /// the show, then starts it again from homing the robots.
+  async fn pause(&mut self) {
+    tracing::warn!("Paused by input");
+    let started = metrics::enter_phase("PAUSED");
+    self.light_manager.stop_cues();
+    self.interrupt().await;
+    tokio::select! {
+      _ = inputs::wait_paused(false) => {}
+      _ = estop::wait_until(true) => {}
+    }
+    metrics::exit_phase("PAUSED", started);
+    tracing::info!("Pause released, restarting");
+  }
+  /// Ends what a phase cut short left running: stops the robots where they
+  /// are, with their moves, and turns the relays off.
+  async fn interrupt(&mut self) {
+    for robot in self.robot_manager.robots() {
+      if let Err(e) = robot.cancel_move().await {
+        tracing::error!(robot = robot.name(), error = %e, "Cannot stop motor");
+      }
+    }
+    self.sparkling_manager.all_turn_off().await;
+  }
+  /// Parks the robots and dims the lights while nobody is around, then
+  /// starts again from homing the robots, with the approach phase.
+  async fn idle(&mut self) {
+    tracing::info!("Nobody around, idling");
+    let started = metrics::enter_phase("IDLE");
+    tokio::select! {
+      _ = self.rest() => {}
+      _ = estop::wait_until(true) => {}
+      _ = inputs::wait_paused(true) => {}
+    }
+    metrics::exit_phase("IDLE", started);
+    if presence::current() != Occupancy::Idle {
+      tracing::info!("Somebody around, restarting");
+      self.next_scene = show::get().presence.approach_phase;
+    }
+  }
+  async fn rest(&mut self) {
+    self.light_manager.stop_cues();
+    let config = &show::get().schedule;
+    if let Err(e) = self
+      .robot_manager
+      .park(config.park_position, config.park_time_ms)
+      .await
+    {
+      tracing::error!(error = %e, "Cannot park the robots");
+    }
+    self.sparkling_manager.all_turn_off().await;
+    // Outside the opening hours the lights stay off
+    if !config.enabled || schedule::current() == Period::Open {
+      let level = show::get().presence.idle_light_level;
+      self.light_manager.set_all([level; 6]).await;
+    }
+    presence::wait_idle(false).await;
+  }
   async fn start(&mut self) {
-    println!("Starting director...");
+    tracing::info!("Starting director...");
+    self.robot_manager.initialize_all().await;
+    self.light_manager.start_cues(self.robot_manager.robots());
+    loop {
+      for phase in CYCLE {
+        self.run_phase(phase).await;
+      }
+    }
+  }
+  /// Follows the opening hours: homes the robots while warming up, runs the
+  /// show while open, finishing the current phase at closing, then parks
+  /// the robots and keeps the lights and relays off until the next opening.
+  async fn start_scheduled(&mut self) {
+    tracing::info!("Starting director on schedule...");
+    let mut homed = false;
+    let mut current: Option<Period> = None;
+    loop {
+      let period = schedule::current();
+      if current != Some(period) {
+        tracing::info!(period = ?period, "Schedule period");
+        metrics::enter_period(period_name(period));
+        match current {
+          Some(Period::Open) => {
+            self.close(homed).await;
+            homed = false;
+          }
+          // Started outside the opening hours, whatever was on goes off
+          None if period != Period::Open => self.close(false).await,
+          _ => {}
+        }
+        current = Some(period);
+      }
+      match period {
+        Period::Closed => {
+          self.keep_relays_off().await;
+          utils::sleep_silent(SCHEDULE_POLL_MS).await;
+        }
+        Period::WarmUp => {
+          if !homed {
+            self.robot_manager.initialize_all().await;
+            homed = true;
+          }
+          utils::sleep_silent(SCHEDULE_POLL_MS).await;
+        }
+        Period::Open => {
+          if !homed {
+            self.robot_manager.initialize_all().await;
+            homed = true;
+          }
+          self.open().await;
+        }
+      }
+    }
+  }
+  /// Runs show cycles until the schedule is no longer open.
+  async fn open(&mut self) {
+    self.light_manager.all_turn_on().await;
+    self.light_manager.start_cues(self.robot_manager.robots());
     loop {
-      self.start_buffering().await;
-      self.start_scanning().await;
-      self.start_buffering().await;
-      self.start_syncing().await;
+      for phase in CYCLE {
+        if schedule::current() != Period::Open {
+          return;
+        }
+        self.run_phase(phase).await;
+      }
+    }
+  }
+  async fn close(&mut self, park: bool) {
+    tracing::info!(park, "Closing");
+    self.light_manager.stop_cues();
+    if park {
+      let config = &show::get().schedule;
+      if let Err(e) = self
+        .robot_manager
+        .park(config.park_position, config.park_time_ms)
+        .await
+      {
+        tracing::error!(error = %e, "Cannot park the robots");
+      }
+    }
+    self.light_manager.all_turn_off().await;
+    self.sparkling_manager.all_turn_off().await;
+  }
+  /// Turns off again any relay not confirmed off, e.g. after a restart of
+  /// the Controllino.
+  async fn keep_relays_off(&self) {
+    for sparkling in self.sparkling_manager.sparklings() {
+      if sparkling.is_on() != Some(false) {
+        sparkling.turn_off().await;
+      }
+    }
+  }
+  /// Runs `phase`, cut short for the scene an input asks for.
+  async fn run_phase(&mut self, phase: Phase) {
+    let mut phase = self.next_scene.take().unwrap_or(phase);
+    loop {
+      let scene = tokio::select! {
+        _ = self.play(phase) => return,
+        scene = inputs::next_scene() => scene,
+      };
+      tracing::info!(from = ?phase, to = ?scene, "Switching scene");
+      self.interrupt().await;
+      phase = scene;
+    }
+  }
+  async fn play(&mut self, phase: Phase) {
+    match phase {
+      Phase::Buffering => self.start_buffering().await,
+      Phase::Scanning => self.start_scanning().await,
+      Phase::Syncing => self.start_syncing().await,
     }
   }
   // async fn move_robot(&mut self){
   //   self.robot_manager.robot_a.set_position(1.0, 1.0).await;
   // }
+  async fn print_status(&self) {
+    for robot in self.robot_manager.robots() {
+      let status = robot.status().await;
+      tracing::info!(
+        robot = robot.name(),
+        state = %status.state,
+        since = %status.since.format("%H:%M:%S"),
+        fault = status.fault,
+        "ROBOT"
+      );
+    }
+    for light in self.light_manager.snapshot() {
+      tracing::info!(
+        light = light.name,
+        level = light.level,
+        acknowledged = light.acknowledged,
+        "LIGHT"
+      );
+    }
+    for sparkling in self.sparkling_manager.sparklings() {
+      let state = match sparkling.is_on() {
+        Some(true) => "ON",
+        Some(false) => "OFF",
+        None => "UNKNOWN",
+      };
+      tracing::info!(sparkling = sparkling.name, state, "SPARKLING");
+    }
+  }
+  #[tracing::instrument(
+    name = "phase",
+    skip(self),
+    fields(phase = "BUFFERING")
+  )]
   async fn start_buffering(&mut self) {
-    println!("BUFFERING STATE INITIATED...");
+    tracing::info!("BUFFERING STATE INITIATED...");
+    let started = metrics::enter_phase("BUFFERING");
+    self.print_status().await;
     self.robot_manager.start_buffering().await;
+    metrics::exit_phase("BUFFERING", started);
   }
+  #[tracing::instrument(name = "phase", skip(self), fields(phase = "SCANNING"))]
   async fn start_scanning(&mut self) {
-    println!("SCANNING STATE INITIATED...");
+    tracing::info!("SCANNING STATE INITIATED...");
+    let started = metrics::enter_phase("SCANNING");
+    self.print_status().await;
     self.robot_manager.start_scanning().await;
     self.sparkling_manager.run_sparkling().await;
+    metrics::exit_phase("SCANNING", started);
   }
+  #[tracing::instrument(name = "phase", skip(self), fields(phase = "SYNCING"))]
   async fn start_syncing(&mut self) {
-    println!("SYNCING STATE INITIATED...");
+    tracing::info!("SYNCING STATE INITIATED...");
+    let started = metrics::enter_phase("SYNCING");
+    self.print_status().await;
     self.robot_manager.start_syncing().await;
     self.light_manager.regulate_light().await;
+    metrics::exit_phase("SYNCING", started);
   }
 }
 
 pub async fn start() {
   std::panic::set_hook(Box::new(|info| {
-    eprintln!("[!] Panic occurred: {info}");
+    tracing::error!("[!] Panic occurred: {info}");
     std::process::exit(1);
   }));
 
+  let config = &show::get().metrics;
+  if config.enabled {
+    tokio::spawn(async {
+      if let Err(e) = metrics::serve(&config.address).await {
+        tracing::error!(error = %e, "Cannot serve metrics");
+      }
+    });
+  }
+
+  let config = &show::get().estop;
+  if let Some(address) = &config.osc_address {
+    tokio::spawn(journal::scope(Origin::Osc, async move {
+      if let Err(e) = estop::listen_osc(address).await {
+        tracing::error!(error = %e, "Cannot listen for OSC emergency stop");
+      }
+    }));
+  }
+  if let Some(address) = &config.http_address {
+    tokio::spawn(async move {
+      if let Err(e) = estop::serve_http(address).await {
+        tracing::error!(error = %e, "Cannot serve HTTP emergency stop");
+      }
+    });
+  }
+  tokio::spawn(journal::scope(Origin::Keyboard, estop::listen_keyboard()));
+  tokio::spawn(journal::scope(
+    Origin::Input,
+    inputs::monitor(&show::get().inputs),
+  ));
+  presence::start(&show::get().presence);
+
   let mut director = Director::new().await;
-  director.start().await;
+  loop {
+    if let Some(latch) = estop::latched() {
+      director.halt(&latch).await;
+    }
+    if inputs::paused() {
+      director.pause().await;
+      continue;
+    }
+    if presence::current() == Occupancy::Idle {
+      director.idle().await;
+      continue;
+    }
+    tokio::select! {
+      _ = director.run() => return,
+      _ = estop::wait_until(true) => {}
+      _ = inputs::wait_paused(true) => {}
+      _ = presence::wait_idle(true) => {}
+    }
+  }
+}
+
+fn period_name(period: Period) -> &'static str {
+  match period {
+    Period::Closed => "CLOSED",
+    Period::WarmUp => "WARM_UP",
+    Period::Open => "OPEN",
+  }
 }
@@ -0,0 +1,351 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::{self, ConfigParam};
+use crate::show;
+use crate::utils;
+
+const FILE_PREFIX: &str = "journal-";
+const FILE_SUFFIX: &str = ".jsonl";
+
+/// Who asked for a command.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+  /// The installation choreography
+  Scene,
+  /// A one-off command line
+  Cli,
+  /// A safety limit, e.g. a sparkling forced off
+  Watchdog,
+  /// An HTTP request, e.g. `POST /estop`
+  Api,
+  /// An OSC message, e.g. `/estop`
+  Osc,
+  /// The console of the installation
+  Keyboard,
+  /// A Controllino input
+  Input,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+  Ok,
+  /// Sent, but the device did not confirm it
+  NotAcknowledged,
+  Failed,
+}
+
+/// One command sent to the hardware, a line of the journal.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+  pub time: DateTime<Local>,
+  pub origin: Origin,
+  /// e.g. `robot A`, `light C`, `sparkling B`
+  pub device: String,
+  pub command: String,
+  pub params: Value,
+  pub result: Outcome,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  /// Only logged, nothing reached the hardware
+  #[serde(default)]
+  pub dry_run: bool,
+}
+
+/// `journal` section of the show file. The journal is written to one file
+/// per day in `dir`, moved aside as `journal-<day>.<n>.jsonl` once it reaches
+/// `max_bytes`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JournalConfig {
+  pub enabled: bool,
+  /// Defaults to `~/triennale-25/journal`
+  pub dir: Option<PathBuf>,
+  pub max_bytes: u64,
+  /// Files of older days are deleted
+  pub keep_days: u32,
+}
+
+impl Default for JournalConfig {
+  fn default() -> Self {
+    JournalConfig {
+      enabled: true,
+      dir: None,
+      max_bytes: 10 * 1024 * 1024,
+      keep_days: 60,
+    }
+  }
+}
+
+impl JournalConfig {
+  pub fn dir(&self) -> PathBuf {
+    self
+      .dir
+      .clone()
+      .unwrap_or_else(|| utils::get_home_dir().join("triennale-25/journal"))
+  }
+}
+
+struct Writer {
+  day: NaiveDate,
+  file: File,
+  size: u64,
+}
+
+static ORIGIN: OnceLock<Origin> = OnceLock::new();
+static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+tokio::task_local! {
+  static TASK_ORIGIN: Origin;
+}
+
+/// Sets the origin of the commands of this process.
+pub fn init(origin: Origin) {
+  ORIGIN.set(origin).expect("Journal already initialized");
+}
+
+/// Runs `future` with its commands journaled as coming from `origin`.
+pub async fn scope<F: Future>(origin: Origin, future: F) -> F::Output {
+  TASK_ORIGIN.scope(origin, future).await
+}
+
+/// Appends a command to the journal. Failing to write it is logged, never
+/// fatal.
+pub fn record(
+  device: &str,
+  command: &str,
+  params: Value,
+  result: Outcome,
+  error: Option<String>,
+) {
+  let config = &show::get().journal;
+  if !config.enabled {
+    return;
+  }
+  let origin = TASK_ORIGIN
+    .try_with(|origin| *origin)
+    .ok()
+    .or(ORIGIN.get().copied())
+    .unwrap_or(Origin::Cli);
+  let entry = Entry {
+    time: Local::now(),
+    origin,
+    device: String::from(device),
+    command: String::from(command),
+    params,
+    result,
+    error,
+    dry_run: config::get(ConfigParam::DRYRUN),
+  };
+  if let Err(e) = write(config, &entry) {
+    tracing::warn!(error = %e, "Cannot write journal");
+  }
+}
+
+/// Appends a command along with how `result` went.
+pub fn record_result<T>(
+  device: &str,
+  command: &str,
+  params: Value,
+  result: &anyhow::Result<T>,
+) {
+  match result {
+    Ok(_) => record(device, command, params, Outcome::Ok, None),
+    Err(e) => record(
+      device,
+      command,
+      params,
+      Outcome::Failed,
+      Some(e.to_string()),
+    ),
+  }
+}
+
+fn write(config: &JournalConfig, entry: &Entry) -> io::Result<()> {
+  let mut line = serde_json::to_string(entry)?;
+  line.push('\n');
+  let day = entry.time.date_naive();
+  let dir = config.dir();
+  let mut writer = WRITER.lock().unwrap();
+  let full = writer.as_ref().is_some_and(|w| {
+    w.size > 0 && w.size + line.len() as u64 > config.max_bytes
+  });
+  if full || writer.as_ref().is_none_or(|w| w.day != day) {
+    *writer = None;
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(file_name(day));
+    if full {
+      rotate(&dir, day, &path)?;
+    }
+    if !path.exists() {
+      prune(&dir, day, config.keep_days);
+    }
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let size = file.metadata()?.len();
+    *writer = Some(Writer { day, file, size });
+  }
+  let writer = writer.as_mut().unwrap();
+  writer.file.write_all(line.as_bytes())?;
+  writer.size += line.len() as u64;
+  Ok(())
+}
+
+fn file_name(day: NaiveDate) -> String {
+  format!("{}{}{}", FILE_PREFIX, day.format("%Y-%m-%d"), FILE_SUFFIX)
+}
+
+/// Day of a journal file, from its name.
+fn file_day(path: &Path) -> Option<NaiveDate> {
+  let name = path.file_name()?.to_str()?;
+  let day = name.strip_prefix(FILE_PREFIX)?.get(..10)?;
+  NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+}
+
+/// Moves the full file of `day` aside, under the next free number.
+fn rotate(dir: &Path, day: NaiveDate, path: &Path) -> io::Result<()> {
+  let base = day.format("%Y-%m-%d");
+  let mut index = 1;
+  loop {
+    let rotated =
+      dir.join(format!("{}{}.{}{}", FILE_PREFIX, base, index, FILE_SUFFIX));
+    if !rotated.exists() {
+      return fs::rename(path, rotated);
+    }
+    index += 1;
+  }
+}
+
+fn prune(dir: &Path, today: NaiveDate, keep_days: u32) {
+  let Some(oldest) =
+    today.checked_sub_signed(TimeDelta::days(keep_days as i64))
+  else {
+    return;
+  };
+  for path in files(dir) {
+    if file_day(&path).is_some_and(|day| day < oldest) {
+      if let Err(e) = fs::remove_file(&path) {
+        tracing::warn!(file = %path.display(), error = %e, "Cannot prune journal");
+      }
+    }
+  }
+}
+
+fn files(dir: &Path) -> Vec<PathBuf> {
+  let Ok(entries) = fs::read_dir(dir) else {
+    return vec![];
+  };
+  entries
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .filter(|path| file_day(path).is_some())
+    .collect()
+}
+
+/// Filter of `director journal`.
+#[derive(Debug, Default)]
+pub struct Query {
+  pub since: Option<DateTime<Local>>,
+  pub until: Option<DateTime<Local>>,
+  /// Part of the device name, e.g. `robot` or `light C`
+  pub device: Option<String>,
+  pub origin: Option<Origin>,
+  pub failed: bool,
+  /// Only the last `limit` matches
+  pub limit: Option<usize>,
+}
+
+impl Query {
+  fn matches(&self, entry: &Entry) -> bool {
+    self.since.is_none_or(|since| entry.time >= since)
+      && self.until.is_none_or(|until| entry.time <= until)
+      && self.device.as_ref().is_none_or(|device| {
+        entry.device.to_lowercase().contains(&device.to_lowercase())
+      })
+      && self.origin.is_none_or(|origin| entry.origin == origin)
+      && (!self.failed || entry.result != Outcome::Ok)
+  }
+}
+
+/// Entries of the journal matching `query`, oldest first.
+pub fn query(query: &Query) -> Vec<Entry> {
+  let dir = show::get().journal.dir();
+  let mut entries = vec![];
+  for path in files(&dir) {
+    let day = file_day(&path).unwrap();
+    if query.since.is_some_and(|since| day < since.date_naive())
+      || query.until.is_some_and(|until| day > until.date_naive())
+    {
+      continue;
+    }
+    let Ok(content) = fs::read_to_string(&path) else {
+      continue;
+    };
+    entries.extend(
+      content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+        .filter(|entry| query.matches(entry)),
+    );
+  }
+  entries.sort_by_key(|entry| entry.time);
+  if let Some(limit) = query.limit {
+    entries.drain(..entries.len().saturating_sub(limit));
+  }
+  entries
+}
+
+/// Parses `2025-05-10`, `2025-05-10 14:30`, `14:30` (today), or a time ago
+/// like `30m`, `2h`, `1d`.
+pub fn parse_time(value: &str) -> anyhow::Result<DateTime<Local>> {
+  let now = Local::now();
+  if let Some(unit) = value.chars().last().filter(|c| "smhd".contains(*c)) {
+    if let Ok(amount) = value[..value.len() - 1].parse::<i64>() {
+      let ago = match unit {
+        's' => TimeDelta::seconds(amount),
+        'm' => TimeDelta::minutes(amount),
+        'h' => TimeDelta::hours(amount),
+        _ => TimeDelta::days(amount),
+      };
+      return Ok(now - ago);
+    }
+  }
+  let naive = if let Ok(time) = NaiveTime::parse_from_str(value, "%H:%M") {
+    now.date_naive().and_time(time)
+  } else if let Ok(day) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+    day.and_time(NaiveTime::MIN)
+  } else {
+    chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M")?
+  };
+  naive
+    .and_local_timezone(Local)
+    .earliest()
+    .ok_or_else(|| anyhow::anyhow!("Invalid local time {}", value))
+}
+
+pub fn print(entry: &Entry) {
+  let result = match entry.result {
+    Outcome::Ok => String::from("ok"),
+    Outcome::NotAcknowledged => String::from("not acknowledged"),
+    Outcome::Failed => {
+      format!("FAILED {}", entry.error.as_deref().unwrap_or(""))
+    }
+  };
+  let origin = format!("{:?}", entry.origin).to_lowercase();
+  println!(
+    "{} {:<8} {:<12} {:<10} {} {}{}",
+    entry.time.format("%Y-%m-%d %H:%M:%S%.3f"),
+    origin,
+    entry.device,
+    entry.command,
+    entry.params,
+    result,
+    if entry.dry_run { " (dry run)" } else { "" }
+  );
+}
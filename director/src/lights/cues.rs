@@ -0,0 +1,175 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use super::fade::Easing;
+use super::Light;
+use crate::robots::{Robot, RobotState};
+use crate::utils;
+
+const LIGHT_NAMES: [&str; 6] = ["A", "B", "C", "D", "E", "F"];
+const ROBOT_NAMES: [&str; 4] = ["A", "B", "C", "D"];
+
+/// How the positions of the robots of a cue are reduced to one value.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Combine {
+  #[default]
+  Average,
+  Min,
+  Max,
+}
+
+/// Level of a light as a function of robot positions, e.g. light A
+/// brightening while robot A moves from 0.3 to 0.0:
+///
+/// ```json
+/// { "light": "A", "robots": ["A"], "from": 0.3, "to": 0.0,
+///   "from_level": 0, "to_level": 10000, "easing": "sine" }
+/// ```
+///
+/// Positions are the mapped ones (0.0-1.0) broadcast on `/robots`. With
+/// `states` the cue only drives the light while all its robots are in one of
+/// them, otherwise the light is left alone.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Cue {
+  pub light: String,
+  pub robots: Vec<String>,
+  #[serde(default)]
+  pub combine: Combine,
+  pub from: f64,
+  pub to: f64,
+  #[serde(default)]
+  pub from_level: u16,
+  pub to_level: u16,
+  #[serde(default = "linear")]
+  pub easing: Easing,
+  #[serde(default)]
+  pub states: Vec<RobotState>,
+}
+
+fn linear() -> Easing {
+  Easing::Linear
+}
+
+impl Cue {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    if !LIGHT_NAMES.contains(&self.light.as_str()) {
+      anyhow::bail!("cue: unknown light {}", self.light);
+    }
+    if self.robots.is_empty() {
+      anyhow::bail!("cue on light {}: needs at least one robot", self.light);
+    }
+    if let Some(robot) = self
+      .robots
+      .iter()
+      .find(|robot| !ROBOT_NAMES.contains(&robot.as_str()))
+    {
+      anyhow::bail!("cue on light {}: unknown robot {}", self.light, robot);
+    }
+    if (self.to - self.from).abs() < f64::EPSILON {
+      anyhow::bail!("cue on light {}: from and to must differ", self.light);
+    }
+    Ok(())
+  }
+
+  /// Level for the given positions of the cue robots.
+  fn level(&self, positions: &[f64]) -> u16 {
+    let position = match self.combine {
+      Combine::Average => {
+        positions.iter().sum::<f64>() / positions.len() as f64
+      }
+      Combine::Min => positions.iter().copied().fold(f64::INFINITY, f64::min),
+      Combine::Max => {
+        positions.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+      }
+    };
+    let t = self
+      .easing
+      .apply((position - self.from) / (self.to - self.from));
+    let from = self.from_level as f64;
+    (from + (self.to_level as f64 - from) * t).round() as u16
+  }
+}
+
+/// Evaluates the cues `rate_hz` times per second and sends the lights they
+/// drive in one frame when a level changed. A cued light takes over any fade
+/// running on it, several cues on the same light keep the brightest level.
+pub async fn run(
+  cues: Vec<Cue>,
+  lights: Vec<Arc<Light>>,
+  robots: Vec<Arc<Robot>>,
+  rate_hz: u32,
+) {
+  tracing::info!(count = cues.len(), rate_hz, "Starting light cues...");
+  for cue in &cues {
+    if let Some(light) = lights.iter().find(|light| light.name == cue.light) {
+      light.journal(
+        "cue",
+        json!({
+          "robots": cue.robots,
+          "from": cue.from,
+          "to": cue.to,
+          "from_level": cue.from_level,
+          "to_level": cue.to_level,
+          "easing": cue.easing,
+        }),
+      );
+    }
+  }
+  let interval_ms = 1000 / rate_hz.max(1) as u64;
+  let robots: HashMap<&str, Arc<Robot>> = robots
+    .into_iter()
+    .map(|robot| (robot.name(), robot))
+    .collect();
+  let mut last_sent: HashMap<&str, u16> = HashMap::new();
+  loop {
+    let mut levels: HashMap<&str, u16> = HashMap::new();
+    for cue in &cues {
+      let mut positions = vec![];
+      let mut active = true;
+      for name in &cue.robots {
+        let robot = &robots[name.as_str()];
+        if !cue.states.is_empty() && !cue.states.contains(&robot.state().await)
+        {
+          active = false;
+          break;
+        }
+        positions.push(robot.position().await);
+      }
+      if !active {
+        continue;
+      }
+      let level = cue.level(&positions);
+      let current = levels.entry(cue.light.as_str()).or_insert(level);
+      *current = (*current).max(level);
+    }
+    // Lights no longer cued are left at their level and free again
+    last_sent.retain(|name, _| levels.contains_key(name));
+    let changed = levels
+      .iter()
+      .any(|(name, level)| last_sent.get(name) != Some(level));
+    if changed {
+      let frame: Vec<u16> = lights
+        .iter()
+        .map(|light| match levels.get(light.name) {
+          Some(level) => {
+            light.fade_generation.fetch_add(1, Ordering::SeqCst);
+            *level
+          }
+          None => light.level(),
+        })
+        .collect();
+      let group = lights.clone();
+      tokio::task::spawn_blocking(move || {
+        super::send_all(&group, &frame, false)
+      })
+      .await
+      .expect("Cue update panicked");
+      last_sent.extend(levels);
+    }
+    utils::sleep_silent(interval_ms).await;
+  }
+}
@@ -0,0 +1,93 @@
+use serde::Deserialize;
+
+const FULL: f64 = 10000.0;
+
+/// Response curve of a fixture, from the level scenes and fades are written
+/// in (perceptual, 0-10000) to the level sent to its driver.
+///
+/// ```json
+/// { "gamma": 2.2, "min_on": 800 }
+/// { "table": [[0, 0], [2500, 400], [5000, 1800], [10000, 10000]] }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Curve {
+  /// Output follows `level ^ gamma`, 1.0 is linear
+  pub gamma: f64,
+  /// `[level, output]` points, interpolated linearly. Replaces `gamma`.
+  pub table: Vec<(u16, u16)>,
+  /// Lowest output the fixture lights up at, any level above 0 starts here
+  pub min_on: u16,
+  /// Output at full level
+  pub max: u16,
+}
+
+impl Default for Curve {
+  fn default() -> Self {
+    Curve {
+      gamma: 1.0,
+      table: vec![],
+      min_on: 0,
+      max: 10000,
+    }
+  }
+}
+
+impl Curve {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    if self.gamma <= 0.0 {
+      anyhow::bail!("gamma must be greater than zero");
+    }
+    if self.max as f64 > FULL || self.min_on >= self.max {
+      anyhow::bail!("min_on must be lower than max, at most 10000");
+    }
+    if self.table.len() == 1 {
+      anyhow::bail!("table needs at least two points");
+    }
+    if self.table.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+      anyhow::bail!("table levels must be increasing");
+    }
+    if self
+      .table
+      .iter()
+      .any(|(level, output)| *level as f64 > FULL || *output as f64 > FULL)
+    {
+      anyhow::bail!("table values must be at most 10000");
+    }
+    Ok(())
+  }
+
+  /// Output level for `level`. 0 is always off.
+  pub fn apply(&self, level: u16) -> u16 {
+    if level == 0 {
+      return 0;
+    }
+    let t = (level as f64 / FULL).min(1.0);
+    let shaped = if self.table.is_empty() {
+      t.powf(self.gamma)
+    } else {
+      self.lookup(level as f64) / FULL
+    };
+    let min_on = self.min_on as f64;
+    (min_on + (self.max as f64 - min_on) * shaped).round() as u16
+  }
+
+  fn lookup(&self, level: f64) -> f64 {
+    let (first, last) = (self.table[0], self.table[self.table.len() - 1]);
+    if level <= first.0 as f64 {
+      return first.1 as f64;
+    }
+    if level >= last.0 as f64 {
+      return last.1 as f64;
+    }
+    let index = self
+      .table
+      .iter()
+      .rposition(|(l, _)| *l as f64 <= level)
+      .unwrap_or(0);
+    let (l0, o0) = self.table[index];
+    let (l1, o1) = self.table[index + 1];
+    let t = (level - l0 as f64) / (l1 - l0) as f64;
+    o0 as f64 + (o1 as f64 - o0 as f64) * t
+  }
+}
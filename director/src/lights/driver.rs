@@ -0,0 +1,576 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{self, ConfigParam};
+use crate::metrics;
+use crate::utils::{MockSerialDevice, RealSerialDevice, SerialDevice};
+
+pub const LIGHT_SERIAL_PORT_NAME: &str = "/dev/ttyACM0";
+pub const LIGHT_SERIAL_BAUD: u32 = 115200;
+
+const DIMMER_CHANNELS: usize = 6;
+const DIMMER_ACK: &str = "All DAC values updated.";
+// The dimmer echoes and parses every command before acknowledging it
+const DIMMER_ACK_MAX_LINES: usize = 4;
+// Answers come within a few ms, a missing one must not hold the driver for
+// the default serial timeout
+const DIMMER_ACK_TIMEOUT_MS: u64 = 250;
+const DIMMER_PONG: &str = "PONG";
+
+const DMX_SLOTS: usize = 512;
+const ARTNET_PORT: u16 = 6454;
+const SACN_PORT: u16 = 5568;
+const SACN_PRIORITY: u8 = 100;
+const SACN_SOURCE_NAME: &str = "triennale-director";
+// Component identifier of the director in sACN packets, any fixed UUID
+const SACN_CID: [u8; 16] = [
+  0x74, 0x72, 0x69, 0x65, 0x6E, 0x6E, 0x61, 0x6C, 0x65, 0x2D, 0x32, 0x35, 0x00,
+  0x00, 0x00, 0x01,
+];
+
+/// Output device of one or more lights. Levels are 0-10000, as for the 0-10 V
+/// dimmer; `channel` is the dimmer output or the DMX start address.
+pub trait LightDriver: Send {
+  /// Sets one channel, returns whether the device confirmed it.
+  fn set_level(&mut self, channel: u16, level: u16) -> anyhow::Result<bool>;
+  /// Sets several channels in a single update.
+  fn set_levels(&mut self, levels: &[(u16, u16)]) -> anyhow::Result<bool>;
+  /// Sends the current levels again, for devices that need a steady stream.
+  fn refresh(&mut self) -> anyhow::Result<()> {
+    Ok(())
+  }
+  /// Whether the device still outputs the levels last sent, for devices that
+  /// report them.
+  fn verify(&mut self) -> anyhow::Result<bool> {
+    Ok(true)
+  }
+}
+
+/// Per light `fixtures` entry of the `lights` section of the show file.
+/// Lights without one use the dimmer on their default channel.
+///
+/// ```json
+/// { "A": { "driver": "artnet", "address": "192.168.1.50:6454",
+///          "universe": 0, "channel": 1, "fine": true },
+///   "B": { "driver": "sacn", "universe": 1, "channel": 10 },
+///   "C": { "driver": "dimmer", "channel": 1 } }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "driver", rename_all = "lowercase")]
+pub enum Fixture {
+  Dimmer { channel: u16 },
+  Artnet(DmxFixture),
+  Sacn(DmxFixture),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DmxFixture {
+  /// Receiver `host:port`. Defaults to the Art-Net broadcast, or to the
+  /// universe multicast group for sACN.
+  pub address: Option<String>,
+  pub universe: u16,
+  /// DMX start address, 1-512
+  pub channel: u16,
+  /// 16 bit level on `channel` (coarse) and `channel + 1` (fine)
+  #[serde(default)]
+  pub fine: bool,
+}
+
+impl Fixture {
+  pub fn channel(&self) -> u16 {
+    match self {
+      Fixture::Dimmer { channel } => *channel,
+      Fixture::Artnet(dmx) | Fixture::Sacn(dmx) => dmx.channel,
+    }
+  }
+
+  pub fn validate(&self) -> anyhow::Result<()> {
+    match self {
+      Fixture::Dimmer { channel } => {
+        if *channel < 1 || *channel as usize > DIMMER_CHANNELS {
+          anyhow::bail!("Invalid dimmer channel {}, use 1-6", channel);
+        }
+      }
+      Fixture::Artnet(dmx) | Fixture::Sacn(dmx) => {
+        let last = if dmx.fine {
+          dmx.channel + 1
+        } else {
+          dmx.channel
+        };
+        if dmx.channel < 1 || last as usize > DMX_SLOTS {
+          anyhow::bail!("Invalid DMX channel {}", dmx.channel);
+        }
+        if matches!(self, Fixture::Sacn(_))
+          && (dmx.universe < 1 || dmx.universe > 63999)
+        {
+          anyhow::bail!("Invalid sACN universe {}, use 1-63999", dmx.universe);
+        }
+        self.address()?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Protocol, receiver and universe of a DMX fixture, one driver each.
+  fn output(&self) -> anyhow::Result<DmxOutput> {
+    match self {
+      Fixture::Dimmer { .. } => anyhow::bail!("The dimmer is not DMX"),
+      Fixture::Artnet(dmx) => {
+        Ok((DmxProtocol::Artnet, self.address()?, dmx.universe))
+      }
+      Fixture::Sacn(dmx) => {
+        Ok((DmxProtocol::Sacn, self.address()?, dmx.universe))
+      }
+    }
+  }
+
+  fn address(&self) -> anyhow::Result<SocketAddr> {
+    let (dmx, default) = match self {
+      Fixture::Dimmer { .. } => anyhow::bail!("The dimmer has no address"),
+      Fixture::Artnet(dmx) => (dmx, format!("255.255.255.255:{}", ARTNET_PORT)),
+      Fixture::Sacn(dmx) => (
+        dmx,
+        format!(
+          "239.255.{}.{}:{}",
+          dmx.universe >> 8,
+          dmx.universe & 0xFF,
+          SACN_PORT
+        ),
+      ),
+    };
+    let address = dmx.address.clone().unwrap_or(default);
+    address
+      .to_socket_addrs()?
+      .next()
+      .ok_or_else(|| anyhow::anyhow!("Cannot resolve {}", address))
+  }
+}
+
+/// Checks no two lights drive the same channel of an output, a `fine` DMX
+/// fixture taking two.
+pub fn validate_channels(fixtures: &[(&str, Fixture)]) -> anyhow::Result<()> {
+  let mut taken: HashMap<(Option<DmxOutput>, u16), &str> = HashMap::new();
+  for (name, fixture) in fixtures {
+    let (output, channels) = match fixture {
+      Fixture::Dimmer { channel } => (None, vec![*channel]),
+      Fixture::Artnet(dmx) | Fixture::Sacn(dmx) => {
+        let channels = if dmx.fine {
+          vec![dmx.channel, dmx.channel + 1]
+        } else {
+          vec![dmx.channel]
+        };
+        (Some(fixture.output()?), channels)
+      }
+    };
+    for channel in channels {
+      if let Some(other) = taken.insert((output, channel), name) {
+        anyhow::bail!(
+          "lights {} and {} both use channel {}",
+          other,
+          name,
+          channel
+        );
+      }
+    }
+  }
+  Ok(())
+}
+
+/// The 0-10 V Arduino dimmer, `DIM` and `SET` commands over serial.
+pub struct DimmerDriver {
+  device: Box<dyn SerialDevice>,
+  levels: [u16; DIMMER_CHANNELS],
+}
+
+impl DimmerDriver {
+  pub async fn open() -> anyhow::Result<Self> {
+    let device: Box<dyn SerialDevice> = if config::get(ConfigParam::DRYRUN) {
+      Box::new(MockSerialDevice::new(
+        LIGHT_SERIAL_PORT_NAME,
+        LIGHT_SERIAL_BAUD,
+      )?)
+    } else {
+      Box::new(
+        RealSerialDevice::new(LIGHT_SERIAL_PORT_NAME, LIGHT_SERIAL_BAUD)
+          .await?,
+      )
+    };
+    let mut driver = DimmerDriver {
+      device,
+      levels: [0; DIMMER_CHANNELS],
+    };
+    driver
+      .device
+      .set_timeout(Duration::from_millis(DIMMER_ACK_TIMEOUT_MS))?;
+    Ok(driver)
+  }
+
+  /// Checks the dimmer answers, returns the levels it currently outputs.
+  pub fn ping(&mut self) -> anyhow::Result<Vec<u16>> {
+    self.send("PING")?;
+    let mut values = vec![];
+    for _ in 0..DIMMER_ACK_MAX_LINES {
+      let line = self.device.read_line()?;
+      if let Some(list) = line.strip_prefix("Values:") {
+        values = list
+          .split_whitespace()
+          .map(|value| value.parse())
+          .collect::<Result<_, _>>()?;
+      } else if line == DIMMER_PONG {
+        return Ok(values);
+      }
+    }
+    anyhow::bail!("No {} from the dimmer", DIMMER_PONG)
+  }
+
+  /// Sends `message`, dropping any answer left from a previous one.
+  fn send(&mut self, message: &str) -> anyhow::Result<()> {
+    self.device.clear_input()?;
+    self.device.send_message(message)
+  }
+
+  fn wait_ack(&mut self) -> bool {
+    for _ in 0..DIMMER_ACK_MAX_LINES {
+      match self.device.read_line() {
+        Ok(line) if line == DIMMER_ACK => return true,
+        Ok(_) => continue,
+        Err(_) => break,
+      }
+    }
+    metrics::failure("serial");
+    false
+  }
+}
+
+impl LightDriver for DimmerDriver {
+  fn set_level(&mut self, channel: u16, level: u16) -> anyhow::Result<bool> {
+    self.send(&format!("DIM {} {}", channel, level))?;
+    self.levels[(channel - 1) as usize] = level;
+    Ok(self.wait_ack())
+  }
+
+  /// Channels not in `levels` keep their last level.
+  fn set_levels(&mut self, levels: &[(u16, u16)]) -> anyhow::Result<bool> {
+    for (channel, level) in levels {
+      self.levels[(*channel - 1) as usize] = *level;
+    }
+    let message = format!(
+      "SET {}",
+      self
+        .levels
+        .iter()
+        .map(|level| level.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+    );
+    self.send(&message)?;
+    Ok(self.wait_ack())
+  }
+
+  fn verify(&mut self) -> anyhow::Result<bool> {
+    let reported = self.ping()?;
+    if reported[..] != self.levels[..] {
+      tracing::warn!(?reported, expected = ?self.levels, "Dimmer levels differ");
+      return Ok(false);
+    }
+    Ok(true)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum DmxProtocol {
+  Artnet,
+  Sacn,
+}
+
+type DmxOutput = (DmxProtocol, SocketAddr, u16);
+
+/// A DMX universe sent over UDP, as Art-Net or sACN (E1.31). The whole
+/// universe is sent on every change, nothing is acknowledged.
+pub struct DmxDriver {
+  socket: UdpSocket,
+  target: SocketAddr,
+  protocol: DmxProtocol,
+  universe: u16,
+  data: [u8; DMX_SLOTS],
+  // Start addresses of the 16 bit fixtures
+  fine_channels: Vec<u16>,
+  sequence: u8,
+}
+
+impl DmxDriver {
+  fn open(fixture: &Fixture) -> anyhow::Result<Self> {
+    let (protocol, dmx) = match fixture {
+      Fixture::Artnet(dmx) => (DmxProtocol::Artnet, dmx),
+      Fixture::Sacn(dmx) => (DmxProtocol::Sacn, dmx),
+      Fixture::Dimmer { .. } => anyhow::bail!("Not a DMX fixture"),
+    };
+    let target = fixture.address()?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+    tracing::info!(
+      protocol = ?protocol,
+      universe = dmx.universe,
+      target = %target,
+      "Initialized DMX output"
+    );
+    Ok(DmxDriver {
+      socket,
+      target,
+      protocol,
+      universe: dmx.universe,
+      data: [0; DMX_SLOTS],
+      fine_channels: vec![],
+      sequence: 0,
+    })
+  }
+
+  fn write(&mut self, channel: u16, level: u16) {
+    let index = (channel - 1) as usize;
+    let level = level.min(10000) as u32;
+    if self.fine_channels.contains(&channel) {
+      let value = (level * 0xFFFF / 10000) as u16;
+      self.data[index..index + 2].copy_from_slice(&value.to_be_bytes());
+    } else {
+      self.data[index] = (level * 0xFF / 10000) as u8;
+    }
+  }
+
+  fn send(&mut self) -> anyhow::Result<()> {
+    // 0 means "no sequencing" for both protocols
+    self.sequence = self.sequence.wrapping_add(1).max(1);
+    let packet = match self.protocol {
+      DmxProtocol::Artnet => {
+        artnet_packet(self.universe, self.sequence, &self.data)
+      }
+      DmxProtocol::Sacn => {
+        sacn_packet(self.universe, self.sequence, &self.data)
+      }
+    };
+    self.socket.send_to(&packet, self.target)?;
+    Ok(())
+  }
+}
+
+impl LightDriver for DmxDriver {
+  fn set_level(&mut self, channel: u16, level: u16) -> anyhow::Result<bool> {
+    self.set_levels(&[(channel, level)])
+  }
+
+  fn set_levels(&mut self, levels: &[(u16, u16)]) -> anyhow::Result<bool> {
+    for (channel, level) in levels {
+      self.write(*channel, *level);
+    }
+    self.send()?;
+    Ok(true)
+  }
+
+  fn refresh(&mut self) -> anyhow::Result<()> {
+    self.send()
+  }
+}
+
+/// ArtDMX packet, Art-Net 4.
+fn artnet_packet(universe: u16, sequence: u8, data: &[u8]) -> Vec<u8> {
+  let mut packet = b"Art-Net\0".to_vec();
+  // OpCode (little endian), then protocol version 14
+  packet.extend_from_slice(&[0x00, 0x50, 0x00, 0x0E]);
+  // Sequence, physical port
+  packet.extend_from_slice(&[sequence, 0x00]);
+  packet.extend_from_slice(&universe.to_le_bytes());
+  packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+  packet.extend_from_slice(data);
+  packet
+}
+
+/// E1.31 data packet: root, framing and DMP layers then the slots.
+fn sacn_packet(universe: u16, sequence: u8, data: &[u8]) -> Vec<u8> {
+  let slots = data.len() + 1;
+  let dmp_len = 10 + slots;
+  let framing_len = 77 + dmp_len;
+  let root_len = 22 + framing_len;
+  let flags = |len: usize| (0x7000 | len as u16).to_be_bytes();
+
+  let mut packet = vec![];
+  // Root layer
+  packet.extend_from_slice(&[0x00, 0x10, 0x00, 0x00]);
+  packet.extend_from_slice(b"ASC-E1.17\0\0\0");
+  packet.extend_from_slice(&flags(root_len));
+  packet.extend_from_slice(&[0x00, 0x00, 0x00, 0x04]);
+  packet.extend_from_slice(&SACN_CID);
+  // Framing layer
+  packet.extend_from_slice(&flags(framing_len));
+  packet.extend_from_slice(&[0x00, 0x00, 0x00, 0x02]);
+  let mut source_name = [0u8; 64];
+  source_name[..SACN_SOURCE_NAME.len()]
+    .copy_from_slice(SACN_SOURCE_NAME.as_bytes());
+  packet.extend_from_slice(&source_name);
+  packet.push(SACN_PRIORITY);
+  // Synchronization address, sequence, options
+  packet.extend_from_slice(&[0x00, 0x00, sequence, 0x00]);
+  packet.extend_from_slice(&universe.to_be_bytes());
+  // DMP layer
+  packet.extend_from_slice(&flags(dmp_len));
+  // Vector, address and data type, first property address, increment
+  packet.extend_from_slice(&[0x02, 0xA1, 0x00, 0x00, 0x00, 0x01]);
+  packet.extend_from_slice(&(slots as u16).to_be_bytes());
+  // DMX start code
+  packet.push(0x00);
+  packet.extend_from_slice(data);
+  packet
+}
+
+/// Drivers opened so far, one per physical output, shared by the lights
+/// using it.
+#[derive(Default)]
+pub struct Drivers {
+  dimmer: Option<Arc<Mutex<dyn LightDriver>>>,
+  dmx: HashMap<DmxOutput, Arc<Mutex<DmxDriver>>>,
+}
+
+impl Drivers {
+  pub fn new() -> Self {
+    Drivers::default()
+  }
+
+  /// Driver of `fixture`, opening its device on first use.
+  pub async fn get(
+    &mut self,
+    fixture: &Fixture,
+  ) -> anyhow::Result<Arc<Mutex<dyn LightDriver>>> {
+    let dmx = match fixture {
+      Fixture::Dimmer { .. } => {
+        if self.dimmer.is_none() {
+          self.dimmer = Some(Arc::new(Mutex::new(DimmerDriver::open().await?)));
+        }
+        return Ok(Arc::clone(self.dimmer.as_ref().unwrap()));
+      }
+      Fixture::Artnet(dmx) | Fixture::Sacn(dmx) => dmx,
+    };
+    let key = fixture.output()?;
+    let driver = match self.dmx.get(&key) {
+      Some(driver) => Arc::clone(driver),
+      None => {
+        let driver = Arc::new(Mutex::new(DmxDriver::open(fixture)?));
+        self.dmx.insert(key, Arc::clone(&driver));
+        driver
+      }
+    };
+    if dmx.fine {
+      driver.lock().unwrap().fine_channels.push(dmx.channel);
+    }
+    Ok(driver)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn receiver() -> (UdpSocket, String) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+      .set_read_timeout(Some(Duration::from_secs(2)))
+      .unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+    (socket, address)
+  }
+
+  fn dmx(address: &str, universe: u16, channel: u16, fine: bool) -> DmxFixture {
+    DmxFixture {
+      address: Some(address.to_string()),
+      universe,
+      channel,
+      fine,
+    }
+  }
+
+  fn receive(socket: &UdpSocket) -> Vec<u8> {
+    let mut buf = [0; 1024];
+    let size = socket.recv(&mut buf).unwrap();
+    buf[..size].to_vec()
+  }
+
+  #[test]
+  fn artnet_packet_carries_universe_and_levels() {
+    let (socket, address) = receiver();
+    let fixture = Fixture::Artnet(dmx(&address, 3, 10, false));
+    let mut driver = DmxDriver::open(&fixture).unwrap();
+    driver.fine_channels.push(20);
+    driver.set_levels(&[(10, 10000), (20, 5000)]).unwrap();
+
+    let packet = receive(&socket);
+    assert_eq!(packet.len(), 18 + DMX_SLOTS);
+    assert_eq!(&packet[..8], b"Art-Net\0");
+    // OpDmx, protocol version 14
+    assert_eq!(&packet[8..12], &[0x00, 0x50, 0x00, 0x0E]);
+    assert_eq!(packet[12], 1, "sequence");
+    assert_eq!(&packet[14..16], &3u16.to_le_bytes());
+    assert_eq!(&packet[16..18], &(DMX_SLOTS as u16).to_be_bytes());
+    let data = &packet[18..];
+    assert_eq!(data[9], 0xFF);
+    assert_eq!(&data[19..21], &0x7FFFu16.to_be_bytes());
+    assert!(data
+      .iter()
+      .enumerate()
+      .all(|(i, v)| [9, 19, 20].contains(&i) || *v == 0));
+
+    driver.refresh().unwrap();
+    assert_eq!(receive(&socket)[12], 2, "sequence");
+  }
+
+  #[test]
+  fn sacn_packet_carries_universe_and_levels() {
+    let (socket, address) = receiver();
+    let fixture = Fixture::Sacn(dmx(&address, 7, 1, false));
+    let mut driver = DmxDriver::open(&fixture).unwrap();
+    driver.set_level(1, 10000).unwrap();
+
+    let packet = receive(&socket);
+    assert_eq!(packet.len(), 126 + DMX_SLOTS);
+    // Preamble, postamble, ACN identifier
+    assert_eq!(&packet[..4], &[0x00, 0x10, 0x00, 0x00]);
+    assert_eq!(&packet[4..16], b"ASC-E1.17\0\0\0");
+    assert_eq!(
+      &packet[16..18],
+      &(0x7000 | (packet.len() - 16) as u16).to_be_bytes()
+    );
+    assert_eq!(&packet[22..38], &SACN_CID);
+    assert_eq!(
+      &packet[44..44 + SACN_SOURCE_NAME.len()],
+      SACN_SOURCE_NAME.as_bytes()
+    );
+    assert_eq!(packet[108], SACN_PRIORITY);
+    assert_eq!(packet[111], 1, "sequence");
+    assert_eq!(&packet[113..115], &7u16.to_be_bytes());
+    assert_eq!(&packet[123..125], &(DMX_SLOTS as u16 + 1).to_be_bytes());
+    assert_eq!(packet[125], 0x00, "start code");
+    assert_eq!(packet[126], 0xFF);
+    assert!(packet[127..].iter().all(|v| *v == 0));
+  }
+
+  #[test]
+  fn colliding_channels_are_rejected() {
+    let address = "127.0.0.1:6454";
+    let dimmer = |channel| Fixture::Dimmer { channel };
+    let fine = Fixture::Artnet(dmx(address, 0, 10, true));
+    assert!(validate_channels(&[
+      ("A", fine.clone()),
+      ("B", Fixture::Artnet(dmx(address, 0, 11, false))),
+    ])
+    .is_err());
+    assert!(validate_channels(&[("A", dimmer(1)), ("B", dimmer(1))]).is_err());
+    // Other universe, protocol or output
+    assert!(validate_channels(&[
+      ("A", fine.clone()),
+      ("B", Fixture::Artnet(dmx(address, 1, 11, false))),
+      ("C", Fixture::Sacn(dmx(address, 1, 10, false))),
+      ("D", dimmer(10)),
+      ("E", Fixture::Artnet(dmx(address, 0, 12, false))),
+    ])
+    .is_ok());
+  }
+}
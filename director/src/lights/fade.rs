@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::f64::consts::PI;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use super::Light;
+use crate::utils;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Easing {
+  Linear,
+  EaseIn,
+  EaseOut,
+  #[default]
+  EaseInOut,
+  Sine,
+}
+
+impl Easing {
+  /// Maps the elapsed fraction of a fade (0.0-1.0) to the fraction of the
+  /// level change to apply.
+  pub fn apply(&self, t: f64) -> f64 {
+    let t = t.clamp(0.0, 1.0);
+    match self {
+      Easing::Linear => t,
+      Easing::EaseIn => t * t * t,
+      Easing::EaseOut => 1.0 - (1.0 - t).powi(3),
+      Easing::EaseInOut => {
+        if t < 0.5 {
+          4.0 * t * t * t
+        } else {
+          1.0 - (-2.0 * t + 2.0).powi(3) / 2.0
+        }
+      }
+      Easing::Sine => (1.0 - (t * PI).cos()) / 2.0,
+    }
+  }
+}
+
+impl std::str::FromStr for Easing {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "linear" => Ok(Easing::Linear),
+      "ease-in" => Ok(Easing::EaseIn),
+      "ease-out" => Ok(Easing::EaseOut),
+      "ease-in-out" => Ok(Easing::EaseInOut),
+      "sine" => Ok(Easing::Sine),
+      _ => anyhow::bail!("Unknown easing {}", s),
+    }
+  }
+}
+
+/// Fades `light` from its current level to `target` in `duration_ms`,
+/// sending a DIM update `rate_hz` times per second. Stops early when another
+/// fade (or a direct level change) takes over the light.
+pub async fn run(
+  light: Arc<Light>,
+  target: u16,
+  duration_ms: u64,
+  easing: Easing,
+  rate_hz: u32,
+) {
+  crate::log_enter!("lights.fade", light.name, target, duration_ms);
+  light.journal(
+    "fade",
+    json!({ "target": target, "duration_ms": duration_ms, "easing": easing }),
+  );
+  let generation = light.fade_generation.fetch_add(1, Ordering::SeqCst) + 1;
+  let from = light.level() as f64;
+  let interval_ms = 1000 / rate_hz.max(1) as u64;
+  let steps = (duration_ms / interval_ms.max(1)).max(1);
+  let mut last_sent = None;
+  for step in 1..=steps {
+    utils::sleep_silent(interval_ms).await;
+    if light.fade_generation.load(Ordering::SeqCst) != generation {
+      crate::log_exit!("lights.fade superseded", light.name);
+      return;
+    }
+    let t = easing.apply(step as f64 / steps as f64);
+    let value = (from + (target as f64 - from) * t).round() as u16;
+    if last_sent == Some(value) {
+      continue;
+    }
+    last_sent = Some(value);
+    // The serial write is blocking, keep it off the async workers
+    let faded = Arc::clone(&light);
+    tokio::task::spawn_blocking(move || faded.send_level(value, false))
+      .await
+      .expect("Fade update panicked");
+  }
+  crate::log_exit!("lights.fade", light.name, target);
+}
+
+/// Fades several lights of the same dimmer together, every step rendered as
+/// a single `SET` frame so they stay in step. `targets[i]` is the final level
+/// of `lights[i]`, `None` leaves that light at its current level. A light
+/// taken over by another fade is left to it, the group stops when it no
+/// longer owns any light.
+pub async fn run_group(
+  lights: Vec<Arc<Light>>,
+  targets: Vec<Option<u16>>,
+  duration_ms: u64,
+  easing: Easing,
+  rate_hz: u32,
+) {
+  crate::log_enter!("lights.fade_group", format!("{:?}", targets));
+  for (light, target) in lights.iter().zip(&targets) {
+    if let Some(target) = target {
+      light.journal(
+        "fade",
+        json!({ "target": target, "duration_ms": duration_ms, "easing": easing }),
+      );
+    }
+  }
+  let generations: Vec<Option<u64>> = lights
+    .iter()
+    .zip(&targets)
+    .map(|(light, target)| {
+      target.map(|_| light.fade_generation.fetch_add(1, Ordering::SeqCst) + 1)
+    })
+    .collect();
+  let from: Vec<f64> =
+    lights.iter().map(|light| light.level() as f64).collect();
+  let interval_ms = 1000 / rate_hz.max(1) as u64;
+  let steps = (duration_ms / interval_ms.max(1)).max(1);
+  let mut last_sent = None;
+  for step in 1..=steps {
+    utils::sleep_silent(interval_ms).await;
+    let t = easing.apply(step as f64 / steps as f64);
+    let mut owned = false;
+    let levels: Vec<u16> = (0..lights.len())
+      .map(|i| match (targets[i], generations[i]) {
+        (Some(target), Some(generation))
+          if lights[i].fade_generation.load(Ordering::SeqCst) == generation =>
+        {
+          owned = true;
+          (from[i] + (target as f64 - from[i]) * t).round() as u16
+        }
+        _ => lights[i].level(),
+      })
+      .collect();
+    if !owned {
+      crate::log_exit!("lights.fade_group superseded", "");
+      return;
+    }
+    if last_sent.as_ref() == Some(&levels) {
+      continue;
+    }
+    last_sent = Some(levels.clone());
+    let group = lights.clone();
+    tokio::task::spawn_blocking(move || {
+      super::send_all(&group, &levels, false)
+    })
+    .await
+    .expect("Fade update panicked");
+  }
+  crate::log_exit!("lights.fade_group", "");
+}
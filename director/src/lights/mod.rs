@@ -1,181 +1,669 @@
 use crate::config::{self, ConfigParam};
-use crate::utils::{
-  self, print_dry_run, MockSerialDevice, RealSerialDevice, SerialDevice,
-};
+use crate::fanout::{self, Stagger};
+use crate::journal::{self, Outcome};
+use crate::metrics;
+use crate::random;
+use crate::robots::Robot;
+use crate::show;
+use crate::utils::{self, print_dry_run};
+use rand::rngs::StdRng;
+use rosc::{encoder, OscMessage, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
 
-pub const LIGHT_SERIAL_PORT_NAME: &'static str = "/dev/ttyACM0";
-pub const LIGHT_SERIAL_BAUD: u32 = 115200;
+pub mod cues;
+pub mod curve;
+pub mod driver;
+pub mod fade;
+use cues::Cue;
+use curve::Curve;
+use driver::{Drivers, Fixture, LightDriver};
+use fade::Easing;
+
+const SERVICE_ADDRESS: &str = "255.255.255.255:6666";
+const SERVICE_INTERVAL_MS: u64 = 1000;
+// Every how many broadcasts unacknowledged levels are sent again
+const RESTORE_EVERY_INTERVALS: u64 = 10;
+// Every how many broadcasts the dimmer is asked for its levels
+const VERIFY_EVERY_INTERVALS: u64 = 10;
+
+const REGULATE_FADE_MS: u64 = 1000 * 8;
+// Dimmer output of each light without a fixture, as in `LightManager::connect`
+const DIMMER_CHANNELS: [(&str, u16); 6] =
+  [("A", 5), ("B", 3), ("C", 1), ("D", 2), ("E", 4), ("F", 6)];
+
+/// `lights` section of the show file.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LightsConfig {
+  /// DIM updates per second while fading, per light
+  pub fade_rate_hz: u32,
+  /// Lights following the robots, evaluated at `fade_rate_hz`
+  pub cues: Vec<Cue>,
+  /// Output of each light by name, see `driver::Fixture`
+  pub fixtures: HashMap<String, Fixture>,
+  /// Response curve of each light by name, linear when missing
+  pub curves: HashMap<String, Curve>,
+}
+
+impl LightsConfig {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    for cue in &self.cues {
+      cue.validate()?;
+    }
+    for (name, fixture) in &self.fixtures {
+      fixture
+        .validate()
+        .map_err(|e| anyhow::anyhow!("fixture {}: {}", name, e))?;
+    }
+    let fixtures: Vec<(&str, Fixture)> = DIMMER_CHANNELS
+      .iter()
+      .map(|(name, channel)| {
+        let fixture = self.fixtures.get(*name).cloned();
+        (
+          *name,
+          fixture.unwrap_or(Fixture::Dimmer { channel: *channel }),
+        )
+      })
+      .collect();
+    driver::validate_channels(&fixtures)?;
+    for (name, curve) in &self.curves {
+      curve
+        .validate()
+        .map_err(|e| anyhow::anyhow!("curve {}: {}", name, e))?;
+    }
+    Ok(())
+  }
+}
+
+impl Default for LightsConfig {
+  fn default() -> Self {
+    LightsConfig {
+      fade_rate_hz: 5,
+      cues: vec![],
+      fixtures: HashMap::new(),
+      curves: HashMap::new(),
+    }
+  }
+}
 
 pub struct LightManager {
-  pub light_a: Light,
-  pub light_b: Light,
-  pub light_c: Light,
-  pub light_d: Light,
-  pub light_e: Light,
-  pub light_f: Light,
+  pub light_a: Arc<Light>,
+  pub light_b: Arc<Light>,
+  pub light_c: Arc<Light>,
+  pub light_d: Arc<Light>,
+  pub light_e: Arc<Light>,
+  pub light_f: Arc<Light>,
+  rng: Mutex<StdRng>,
+  cues: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl LightManager {
   pub async fn new() -> Self {
-    let serial_device: Arc<Mutex<dyn SerialDevice>> =
-      if config::get(ConfigParam::DRYRUN) {
-        Arc::new(Mutex::new(
-          MockSerialDevice::new(LIGHT_SERIAL_PORT_NAME, LIGHT_SERIAL_BAUD)
-            .expect("Cannot initialize MockSerialDevice"),
-        ))
-      } else {
-        Arc::new(Mutex::new(
-          RealSerialDevice::new(LIGHT_SERIAL_PORT_NAME, LIGHT_SERIAL_BAUD)
-            .await
-            .expect("Cannot initialize RealSerialDevice"),
-        ))
-      };
-
-    let mut light_manager: LightManager = LightManager {
-      light_a: Light::new(0, "A", 5, Arc::clone(&serial_device)).await,
-      light_b: Light::new(1, "B", 3, Arc::clone(&serial_device)).await,
-      light_c: Light::new(2, "C", 1, Arc::clone(&serial_device)).await,
-      light_d: Light::new(3, "D", 2, Arc::clone(&serial_device)).await,
-      light_e: Light::new(4, "E", 4, Arc::clone(&serial_device)).await,
-      light_f: Light::new(5, "F", 6, Arc::clone(&serial_device)).await,
-    };
-    light_manager.all_turn_on().await;
-    utils::sleep(5000, "LightManager new").await;
-    light_manager.all_turn_off().await;
-    utils::sleep(5000, "LightManager new").await;
+    let mut light_manager = LightManager::connect().await;
+    let self_test = show::get().self_test;
+    if self_test.enabled {
+      light_manager.all_turn_on().await;
+      utils::sleep(self_test.hold_ms, "LightManager new").await;
+      light_manager.all_turn_off().await;
+      utils::sleep(self_test.hold_ms, "LightManager new").await;
+    }
     light_manager.all_turn_on().await;
+    let lights = light_manager.lights();
+    tokio::spawn(async move {
+      start_service(lights).await;
+    });
     return light_manager;
   }
 
+  /// Opens the dimmer without the startup blink and broadcast, for one-off
+  /// commands.
+  pub async fn connect() -> Self {
+    let mut drivers = Drivers::new();
+    LightManager {
+      light_a: Arc::new(Light::new(0, "A", 5, &mut drivers).await),
+      light_b: Arc::new(Light::new(1, "B", 3, &mut drivers).await),
+      light_c: Arc::new(Light::new(2, "C", 1, &mut drivers).await),
+      light_d: Arc::new(Light::new(3, "D", 2, &mut drivers).await),
+      light_e: Arc::new(Light::new(4, "E", 4, &mut drivers).await),
+      light_f: Arc::new(Light::new(5, "F", 6, &mut drivers).await),
+      rng: Mutex::new(random::fork("LightManager")),
+      cues: Mutex::new(None),
+    }
+  }
+
+  pub fn lights(&self) -> Vec<Arc<Light>> {
+    vec![
+      Arc::clone(&self.light_a),
+      Arc::clone(&self.light_b),
+      Arc::clone(&self.light_c),
+      Arc::clone(&self.light_d),
+      Arc::clone(&self.light_e),
+      Arc::clone(&self.light_f),
+    ]
+  }
+
+  pub fn light(&self, name: &str) -> Option<Arc<Light>> {
+    self.lights().into_iter().find(|light| light.name == name)
+  }
+
+  /// Sets every light at once, levels from light A to F, in a single frame.
+  pub async fn set_all(&self, levels: [u16; 6]) {
+    let lights = self.lights();
+    tokio::task::spawn_blocking(move || set_all(&lights, &levels))
+      .await
+      .expect("Set all panicked");
+  }
+
+  /// Last commanded state of every light.
+  pub fn snapshot(&self) -> Vec<LightStatus> {
+    self.lights().iter().map(|light| light.status()).collect()
+  }
+
+  /// Starts driving the cued lights from the robots, if the show has cues.
+  pub fn start_cues(&self, robots: Vec<Arc<Robot>>) {
+    let config = &show::get().lights;
+    if config.cues.is_empty() {
+      return;
+    }
+    let handle = tokio::spawn(cues::run(
+      config.cues.clone(),
+      self.lights(),
+      robots,
+      config.fade_rate_hz,
+    ));
+    if let Some(previous) = self.cues.lock().unwrap().replace(handle) {
+      previous.abort();
+    }
+  }
+
+  /// Leaves the cued lights at their level, free for other commands.
+  pub fn stop_cues(&self) {
+    if let Some(handle) = self.cues.lock().unwrap().take() {
+      handle.abort();
+    }
+  }
+
   pub async fn regulate_light(&mut self) {
-    self.light_a.dim(5000);
+    // Runs alongside the robots, the handle is not awaited
+    self.fade(&self.light_a, 5000, REGULATE_FADE_MS, Easing::EaseInOut);
+  }
+
+  /// Starts a fade of `light` from its current level to `target`. Fades of
+  /// different lights run concurrently, a new fade on the same light takes
+  /// over the previous one.
+  pub fn fade(
+    &self,
+    light: &Arc<Light>,
+    target: u16,
+    duration_ms: u64,
+    easing: Easing,
+  ) -> JoinHandle<()> {
+    tokio::spawn(fade::run(
+      Arc::clone(light),
+      target,
+      duration_ms,
+      easing,
+      show::get().lights.fade_rate_hz,
+    ))
+  }
+
+  /// Fades the `from` group out while the `to` group fades in to `level`,
+  /// every step of all the lights sent in one frame.
+  pub fn cross_fade(
+    &self,
+    from: &[Arc<Light>],
+    to: &[Arc<Light>],
+    level: u16,
+    duration_ms: u64,
+    easing: Easing,
+  ) -> JoinHandle<()> {
+    let lights = self.lights();
+    let targets = lights
+      .iter()
+      .map(|light| {
+        if to.iter().any(|l| Arc::ptr_eq(l, light)) {
+          Some(level)
+        } else if from.iter().any(|l| Arc::ptr_eq(l, light)) {
+          Some(0)
+        } else {
+          None
+        }
+      })
+      .collect();
+    tokio::spawn(fade::run_group(
+      lights,
+      targets,
+      duration_ms,
+      easing,
+      show::get().lights.fade_rate_hz,
+    ))
   }
 
   pub async fn all_turn_on(&mut self) {
     crate::log_enter!("lights.all_turn_on", "");
-    self.light_a.turn_on();
-    utils::sleep(2000, "LightManager all_turn_on").await;
-    self.light_b.turn_on();
-    utils::sleep(2000, "LightManager all_turn_on").await;
-    // self.light_c.turn_on();
-    utils::sleep(2000, "LightManager all_turn_on").await;
-    self.light_d.turn_on();
-    utils::sleep(2000, "LightManager all_turn_on").await;
-    self.light_e.turn_on();
-    utils::sleep(2000, "LightManager all_turn_on").await;
-    self.light_f.turn_on();
+    // Light C is left off
+    let lights = self
+      .lights()
+      .into_iter()
+      .filter(|light| light.name != "C")
+      .collect();
+    self.switch_all(lights, true).await;
     crate::log_exit!("lights.all_turn_on", "");
   }
 
   pub async fn all_turn_off(&mut self) {
     crate::log_enter!("lights.all_turn_off", "");
-    self.light_a.turn_off();
-    utils::sleep(2000, "LightManager all_turn_off").await;
-    self.light_b.turn_off();
-    utils::sleep(2000, "LightManager all_turn_off").await;
-    self.light_c.turn_off();
-    utils::sleep(2000, "LightManager all_turn_off").await;
-    self.light_d.turn_off();
-    utils::sleep(2000, "LightManager all_turn_off").await;
-    self.light_e.turn_off();
-    utils::sleep(2000, "LightManager all_turn_off").await;
-    self.light_f.turn_off();
+    self.switch_all(self.lights(), false).await;
     crate::log_exit!("lights.all_turn_off", "");
   }
+
+  /// Turns the lights on or off following the lights stagger of the show,
+  /// all at once in a single frame when simultaneous.
+  async fn switch_all(&self, lights: Vec<Arc<Light>>, on: bool) {
+    let stagger = show::get().fanout.lights;
+    if let Stagger::Simultaneous = stagger {
+      for light in &lights {
+        light.fade_generation.fetch_add(1, Ordering::SeqCst);
+      }
+      let levels = vec![if on { 10000 } else { 0 }; lights.len()];
+      tokio::task::spawn_blocking(move || set_all(&lights, &levels))
+        .await
+        .expect("Switch all panicked");
+      return;
+    }
+    fanout::run(stagger, lights, &self.rng, |light| async move {
+      tokio::task::spawn_blocking(move || {
+        if on {
+          light.turn_on()
+        } else {
+          light.turn_off()
+        }
+      })
+      .await
+      .expect("Switch panicked");
+    })
+    .await;
+  }
 }
 
 pub struct Light {
   pub id: u8,
   pub name: &'static str,
-  pub serial_channel: u8,
-  pub serial_device: Arc<Mutex<dyn SerialDevice>>,
+  pub fixture: Fixture,
+  pub curve: Curve,
+  driver: Arc<Mutex<dyn LightDriver>>,
+  status: Mutex<LightStatus>,
+  // Bumped by every fade and direct change, a running fade stops as soon as
+  // it no longer owns the latest generation
+  fade_generation: AtomicU64,
 }
 
 impl Light {
+  /// Light on its show file fixture, or on `dimmer_channel` of the dimmer.
   pub async fn new(
     id: u8,
     name: &'static str,
-    serial_channel: u8,
-    serial_device: Arc<Mutex<dyn SerialDevice>>,
+    dimmer_channel: u16,
+    drivers: &mut Drivers,
   ) -> Self {
+    let fixture = show::get().lights.fixtures.get(name).cloned().unwrap_or(
+      Fixture::Dimmer {
+        channel: dimmer_channel,
+      },
+    );
+    let driver = drivers
+      .get(&fixture)
+      .await
+      .expect("Cannot initialize light driver");
+    let curve = show::get()
+      .lights
+      .curves
+      .get(name)
+      .cloned()
+      .unwrap_or_default();
     let light: Light = Light {
       id,
       name,
-      serial_device,
-      serial_channel,
+      fixture,
+      curve,
+      driver,
+      status: Mutex::new(LightStatus {
+        name,
+        level: 0,
+        acknowledged: false,
+        updated_at_ms: 0,
+      }),
+      fade_generation: AtomicU64::new(0),
     };
     light.print();
     return light;
   }
 
-  pub fn dim(&mut self, value: u16) {
+  /// Last level sent to the light, before its curve
+  pub fn level(&self) -> u16 {
+    self.status.lock().unwrap().level
+  }
+
+  pub fn status(&self) -> LightStatus {
+    self.status.lock().unwrap().clone()
+  }
+
+  pub fn dim(&self, value: u16) {
+    self.fade_generation.fetch_add(1, Ordering::SeqCst);
+    self.send_level(value, true);
+  }
+
+  /// Sends `value`, journaled unless it is a step of a fade or cue, which
+  /// journal their command once.
+  fn send_level(&self, value: u16, journaled: bool) {
     crate::log_enter!("lights.dim", self.name);
     if config::get(ConfigParam::DRYRUN) {
-      print_dry_run(format!("LIGHT [{}] dimmed {}", self.name, value).as_str());
+      print_dry_run(
+        format!(
+          "LIGHT [{}] dimmed {} (output {})",
+          self.name,
+          value,
+          self.curve.apply(value)
+        )
+        .as_str(),
+      );
+      self.record(value, true, journaled);
       crate::log_exit!("lights.dim", self.name);
       return;
     }
-    let message = format!("DIM {} {}", self.serial_channel, value);
-    self
-      .serial_device
-      .lock()
-      .unwrap()
-      .send_message(&message)
-      .expect("failed to send on message dim");
+    self.write_level(value, journaled);
     crate::log_exit!("lights.dim", self.name);
   }
 
-  pub fn turn_on(&mut self) {
+  pub fn turn_on(&self) {
     crate::log_enter!("lights.turn_on", self.name);
+    self.fade_generation.fetch_add(1, Ordering::SeqCst);
     if config::get(ConfigParam::DRYRUN) {
       print_dry_run(format!("LIGHT [{}] turned ON", self.name).as_str());
+      self.record(10000, true, true);
       crate::log_exit!("lights.turn_on", self.name);
       return;
     }
-    let message = format!("DIM {} 10000", self.serial_channel);
-    self
-      .serial_device
-      .lock()
-      .unwrap()
-      .send_message(&message)
-      .expect("failed to send on message on");
+    self.write_level(10000, true);
     crate::log_exit!("lights.turn_on", self.name);
   }
 
-  pub fn turn_off(&mut self) {
+  pub fn turn_off(&self) {
     crate::log_enter!("lights.turn_off", self.name);
+    self.fade_generation.fetch_add(1, Ordering::SeqCst);
     if config::get(ConfigParam::DRYRUN) {
       print_dry_run(format!("LIGHT [{}] turned OFF", self.name).as_str());
+      self.record(0, true, true);
       crate::log_exit!("lights.turn_off", self.name);
       return;
     }
-    let message = format!("DIM {} 0", self.serial_channel);
-    self
-      .serial_device
+    self.write_level(0, true);
+    crate::log_exit!("lights.turn_off", self.name);
+  }
+
+  /// Sends the level to the driver and records it along with whether the
+  /// device acknowledged it. A level that could not be sent is recorded as
+  /// not acknowledged, for the light service to send it again.
+  fn write_level(&self, value: u16, journaled: bool) {
+    let result = self
+      .driver
       .lock()
       .unwrap()
-      .send_message(&message)
-      .expect("failed to send on message off");
-    crate::log_exit!("lights.turn_off", self.name);
+      .set_level(self.fixture.channel(), self.curve.apply(value));
+    let acknowledged = match result {
+      Ok(true) => true,
+      Ok(false) => {
+        tracing::warn!(light = self.name, level = value, "Not acknowledged");
+        false
+      }
+      Err(e) => {
+        tracing::error!(light = self.name, error = %e, "Send failed");
+        false
+      }
+    };
+    self.record(value, acknowledged, journaled);
+  }
+
+  fn record(&self, level: u16, acknowledged: bool, journaled: bool) {
+    if journaled {
+      journal::record(
+        &format!("light {}", self.name),
+        "level",
+        json!({ "level": level, "output": self.curve.apply(level) }),
+        if acknowledged {
+          Outcome::Ok
+        } else {
+          Outcome::NotAcknowledged
+        },
+        None,
+      );
+    }
+    let mut status = self.status.lock().unwrap();
+    status.level = level;
+    status.acknowledged = acknowledged;
+    status.updated_at_ms = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_millis() as u64)
+      .unwrap_or(0);
+  }
+
+  fn journal(&self, command: &str, params: serde_json::Value) {
+    journal::record(
+      &format!("light {}", self.name),
+      command,
+      params,
+      Outcome::Ok,
+      None,
+    );
   }
 
   fn print(&self) {
-    println!("LIGHT {} {}", self.id, self.name);
+    tracing::info!(
+      id = self.id,
+      light = self.name,
+      fixture = ?self.fixture,
+      "LIGHT"
+    );
+  }
+}
+
+/// Last commanded state of a light.
+#[derive(Debug, Clone, Serialize)]
+pub struct LightStatus {
+  pub name: &'static str,
+  pub level: u16,
+  /// Whether the dimmer confirmed it applied `level`
+  pub acknowledged: bool,
+  /// Unix time of the last command, in milliseconds
+  pub updated_at_ms: u64,
+}
+
+/// Sends the levels of several lights together, one update per driver, so
+/// the fixtures on the same device change at once. `levels[i]` goes to
+/// `lights[i]`, through its curve.
+pub fn set_all(lights: &[Arc<Light>], levels: &[u16]) {
+  send_all(lights, levels, true);
+}
+
+/// Same as `set_all`, journaled unless it is a step of a fade or cue.
+fn send_all(lights: &[Arc<Light>], levels: &[u16], journaled: bool) {
+  crate::log_enter!("lights.set_all", format!("{:?}", levels));
+  if config::get(ConfigParam::DRYRUN) {
+    let message = lights
+      .iter()
+      .zip(levels)
+      .map(|(light, level)| {
+        format!("{}={} -> {}", light.name, level, light.curve.apply(*level))
+      })
+      .collect::<Vec<_>>()
+      .join(", ");
+    print_dry_run(format!("LIGHTS SET {}", message).as_str());
+    for (light, level) in lights.iter().zip(levels) {
+      light.record(*level, true, journaled);
+    }
+    crate::log_exit!("lights.set_all", true);
+    return;
+  }
+  let mut pending: Vec<(&Arc<Light>, u16)> =
+    lights.iter().zip(levels.iter().copied()).collect();
+  while let Some((first, _)) = pending.first() {
+    let driver = Arc::clone(&first.driver);
+    let (group, rest): (Vec<_>, Vec<_>) = pending
+      .into_iter()
+      .partition(|(light, _)| Arc::ptr_eq(&light.driver, &driver));
+    pending = rest;
+    let channels: Vec<(u16, u16)> = group
+      .iter()
+      .map(|(light, level)| {
+        (light.fixture.channel(), light.curve.apply(*level))
+      })
+      .collect();
+    let result = driver.lock().unwrap().set_levels(&channels);
+    let acknowledged = match result {
+      Ok(true) => true,
+      Ok(false) => {
+        tracing::warn!(channels = ?channels, "Not acknowledged");
+        false
+      }
+      Err(e) => {
+        tracing::error!(channels = ?channels, error = %e, "Send failed");
+        false
+      }
+    };
+    for (light, level) in group {
+      light.record(level, acknowledged, journaled);
+    }
+  }
+  crate::log_exit!("lights.set_all", "");
+}
+
+/// Sends the last known level of every light again, e.g. after the dimmer
+/// was reset and came back with its boot values.
+fn restore(lights: &[Arc<Light>]) {
+  for light in lights {
+    let level = light.level();
+    tracing::info!(light = light.name, level, "Restoring level");
+    light.send_level(level, true);
+  }
+}
+
+/// Broadcasts the light levels over OSC (`/lights`, one level per light
+/// from A to F) and sends them again when the dimmer stopped acknowledging
+/// or reports other levels, e.g. after it was reset.
+async fn start_service(lights: Vec<Arc<Light>>) {
+  tracing::info!("Starting Light Service...");
+  let socket = UdpSocket::bind("0.0.0.0:0").expect("could not bind socket");
+  socket
+    .set_broadcast(true)
+    .expect("could not enable broadcast");
+
+  let mut since_restore: u64 = 0;
+  let mut since_verify: u64 = 0;
+  loop {
+    let statuses: Vec<LightStatus> =
+      lights.iter().map(|light| light.status()).collect();
+    let msg_string = statuses
+      .iter()
+      .map(|status| status.level.to_string())
+      .collect::<Vec<_>>()
+      .join(",");
+
+    let msg = OscMessage {
+      addr: "/lights".into(),
+      args: vec![OscType::String(msg_string)],
+    };
+    let packet = OscPacket::Message(msg);
+    let buf = encoder::encode(&packet).unwrap();
+    socket.send_to(&buf, SERVICE_ADDRESS).unwrap();
+
+    since_restore += 1;
+    let unacknowledged = statuses.iter().any(|status| !status.acknowledged);
+    if unacknowledged && since_restore >= RESTORE_EVERY_INTERVALS {
+      since_restore = 0;
+      tracing::warn!("Dimmer did not acknowledge, restoring last known levels");
+      let lights = lights.clone();
+      tokio::task::spawn_blocking(move || restore(&lights))
+        .await
+        .expect("Restore panicked");
+    }
+
+    if !config::get(ConfigParam::DRYRUN) {
+      let lights = lights.clone();
+      tokio::task::spawn_blocking(move || refresh(&lights))
+        .await
+        .expect("Refresh panicked");
+    }
+
+    since_verify += 1;
+    if since_verify >= VERIFY_EVERY_INTERVALS
+      && !config::get(ConfigParam::DRYRUN)
+    {
+      since_verify = 0;
+      let lights = lights.clone();
+      tokio::task::spawn_blocking(move || {
+        if !verify(&lights) {
+          tracing::warn!("Restoring last known levels");
+          restore(&lights);
+        }
+      })
+      .await
+      .expect("Verify panicked");
+    }
+
+    utils::sleep_silent(SERVICE_INTERVAL_MS).await;
+  }
+}
+
+/// Keeps the streaming drivers (DMX) alive between changes.
+fn refresh(lights: &[Arc<Light>]) {
+  let mut refreshed: Vec<&Arc<Mutex<dyn LightDriver>>> = vec![];
+  for light in lights {
+    if refreshed.iter().any(|d| Arc::ptr_eq(d, &light.driver)) {
+      continue;
+    }
+    refreshed.push(&light.driver);
+    if let Err(e) = light.driver.lock().unwrap().refresh() {
+      tracing::error!(light = light.name, error = %e, "Refresh failed");
+    }
+  }
+}
+
+/// Whether every driver still outputs the levels last sent to it.
+fn verify(lights: &[Arc<Light>]) -> bool {
+  let mut verified: Vec<&Arc<Mutex<dyn LightDriver>>> = vec![];
+  let mut matching = true;
+  for light in lights {
+    if verified.iter().any(|d| Arc::ptr_eq(d, &light.driver)) {
+      continue;
+    }
+    verified.push(&light.driver);
+    match light.driver.lock().unwrap().verify() {
+      Ok(true) => {}
+      Ok(false) => matching = false,
+      Err(e) => {
+        metrics::failure("serial");
+        tracing::error!(light = light.name, error = %e, "Verify failed");
+      }
+    }
   }
+  matching
 }
 
-pub async fn create(
-  id: &str,
-  serial_device: Arc<Mutex<dyn SerialDevice>>,
-) -> Light {
+pub async fn create(id: &str, drivers: &mut Drivers) -> Light {
   match id {
-    "1" => Light::new(0, "C", 1, Arc::clone(&serial_device)).await,
-    "2" => Light::new(1, "D", 2, Arc::clone(&serial_device)).await,
-    "3" => Light::new(2, "B", 3, Arc::clone(&serial_device)).await,
-    "4" => Light::new(3, "E", 4, Arc::clone(&serial_device)).await,
-    "5" => Light::new(4, "A", 6, Arc::clone(&serial_device)).await,
-    "6" => Light::new(5, "F", 5, Arc::clone(&serial_device)).await,
+    "1" => Light::new(0, "C", 1, drivers).await,
+    "2" => Light::new(1, "D", 2, drivers).await,
+    "3" => Light::new(2, "B", 3, drivers).await,
+    "4" => Light::new(3, "E", 4, drivers).await,
+    "5" => Light::new(4, "A", 6, drivers).await,
+    "6" => Light::new(5, "F", 5, drivers).await,
     _ => {
       panic!("Invalid Light ID. Possible value [1-6]");
     }
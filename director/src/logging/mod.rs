@@ -0,0 +1,42 @@
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// Sets up the global logger. The console gets human readable lines, or JSON
+/// lines with `json` (for journald); `file` gets JSON lines too. Levels are
+/// `info` and up, `debug` with `--debug`, or as set in `RUST_LOG` (e.g.
+/// `RUST_LOG=director::robots=debug,director::lights=warn`).
+pub fn init(debug: bool, json: bool, file: Option<&str>) -> anyhow::Result<()> {
+  let default_filter = if debug {
+    "director=debug"
+  } else {
+    "director=info"
+  };
+  let filter = EnvFilter::try_from_default_env()
+    .unwrap_or_else(|_| EnvFilter::new(default_filter));
+  let console = if json {
+    fmt::layer().json().with_span_list(true).boxed()
+  } else {
+    fmt::layer().boxed()
+  };
+  let file = match file {
+    Some(path) => {
+      let file = OpenOptions::new().create(true).append(true).open(path)?;
+      Some(
+        fmt::layer()
+          .json()
+          .with_span_list(true)
+          .with_writer(Mutex::new(file))
+          .boxed(),
+      )
+    }
+    None => None,
+  };
+  tracing_subscriber::registry()
+    .with(filter)
+    .with(console)
+    .with(file)
+    .try_init()?;
+  Ok(())
+}
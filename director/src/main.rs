@@ -1,12 +1,25 @@
-use crate::utils::SerialDevice;
 use tokio::time::{Duration};
 
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
 
+mod estop;
+mod fanout;
+mod inputs;
 mod installation;
+mod journal;
 mod lights;
+mod logging;
+mod metrics;
+mod motors;
+mod params;
+mod presence;
+mod random;
 mod robots;
+mod schedule;
+mod selftest;
+mod show;
 mod sparklings;
 mod utils;
 
@@ -24,9 +37,29 @@ async fn main() {
 
   let mut debug = true;
   let mut dry_run = true;
+  let mut seed: Option<u64> = None;
+  let mut show_path: Option<String> = None;
+  let mut log_json = false;
+  let mut log_file: Option<String> = None;
 
   let mut args = env::args().collect::<Vec<_>>();
 
+  // Look for global flags with a value and remove them from `args`
+  let mut index = 0;
+  while index < args.len() {
+    match args[index].as_str() {
+      "--seed" | "--show" | "--log-file" if index + 1 < args.len() => {
+        let value = args.remove(index + 1);
+        match args.remove(index).as_str() {
+          "--seed" => seed = Some(value.parse().expect("Invalid seed value")),
+          "--show" => show_path = Some(value),
+          _ => log_file = Some(value),
+        }
+      }
+      _ => index += 1,
+    }
+  }
+
   // Look for global flags and remove them from `args`
   args.retain(|arg| match arg.as_str() {
     "--debug" => {
@@ -45,28 +78,57 @@ async fn main() {
       dry_run = false;
       false
     }
+    "--log-json" => {
+      log_json = true;
+      false
+    }
     _ => true,
   });
 
+  logging::init(debug, log_json, log_file.as_deref())
+    .expect("Cannot initialize logging");
+
+  let show = match show_path {
+    Some(path) => show::load(&path).expect("Cannot load show file"),
+    None => show::Show::default(),
+  };
+  // The command line wins over the show file
+  let seed = seed.or(show.seed).unwrap_or_else(random::generate_seed);
+  show::SHOW.set(show).expect("Show already set");
+
   // Initialize global config
   CONFIG
-    .set(Config { debug, dry_run })
+    .set(Config { dry_run, seed })
     .expect("Config already set");
 
-  println!("Selected config: {:?}", CONFIG.get().unwrap());
+  tracing::info!("Selected config: {:?}", CONFIG.get().unwrap());
+  tracing::info!(seed, "Random seed: {} (replay with --seed {})", seed, seed);
+
+  journal::init(match args[1].as_str() {
+    "installation" | "i" => journal::Origin::Scene,
+    _ => journal::Origin::Cli,
+  });
 
   match args[1].as_str() {
     "lights" | "l" => handle_lights(&args).await,
     "sparklings" | "s" => handle_sparklings(&args).await,
     "robots" | "r" => handle_robots(&args).await,
     "installation" | "i" => handle_installation(&args).await,
+    "selftest" => handle_selftest(&args).await,
+    "journal" | "j" => handle_journal(&args),
+    "schedule" => handle_schedule(&args),
+    "params" | "p" => handle_params(&args),
+    "estop" | "e" => handle_estop(&args).await,
+    "inputs" => handle_inputs().await,
     _ => eprintln!("Unknown command: {}", args[1]),
   }
 }
 
 async fn handle_robots(args: &[String]) {
   if args.len() < 4 {
-    eprintln!("Usage: cargo run robots <ID> <init/move> [<pos> <speed>]");
+    eprintln!(
+      "Usage: cargo run robots <ID> <init/move/stop/trajectory/breathe/sync/coordinate> [args...]"
+    );
     return;
   }
 
@@ -121,7 +183,129 @@ async fn handle_robots(args: &[String]) {
       // let robot = robots::create(id);
       let pos: f64 = pos_str.parse().expect("Invalid position value");
       let speed: f64 = speed_str.parse().expect("Invalid speed value");
-      robot.set_position(pos, speed).await;
+      drive_move(robot.start_move(pos, speed)).await;
+    }
+    "stop" => {
+      println!("Stopping robot ID: {}", id);
+      let robot_manager = robots::RobotManager::new().await;
+      let robot = match id.as_str() {
+        "1" => robot_manager.robot_b,
+        "2" => robot_manager.robot_c,
+        "3" => robot_manager.robot_a,
+        "4" => robot_manager.robot_d,
+        _ => panic!("Invalid Robot ID: {}", id),
+      };
+      robot.cancel_move().await.expect("Cannot stop robot");
+    }
+    "trajectory" | "breathe" => {
+      let trajectory = if command == "trajectory" {
+        if args.len() < 5 {
+          eprintln!(
+            "Usage: cargo run robots <ID> trajectory <pos>:<speed>[:<dwell_ms>]..."
+          );
+          return;
+        }
+        robots::trajectory::Trajectory::parse_waypoints(&args[4..])
+          .expect("Invalid trajectory")
+      } else {
+        if args.len() < 8 {
+          eprintln!(
+            "Usage: cargo run robots <ID> breathe <center> <amplitude> <period_s> <cycles>"
+          );
+          return;
+        }
+        let center: f64 = args[4].parse().expect("Invalid center value");
+        let amplitude: f64 = args[5].parse().expect("Invalid amplitude value");
+        let period: f64 = args[6].parse().expect("Invalid period value");
+        let cycles: u32 = args[7].parse().expect("Invalid cycles value");
+        robots::trajectory::Trajectory::breathing(
+          center,
+          amplitude,
+          (period * 1000.0) as u64,
+          cycles,
+          1000,
+        )
+      };
+      println!("Running trajectory on robot ID: {}: {:?}", id, trajectory);
+      let robot_manager = robots::RobotManager::new().await;
+      let robot = match id.as_str() {
+        "1" => robot_manager.robot_b,
+        "2" => robot_manager.robot_c,
+        "3" => robot_manager.robot_a,
+        "4" => robot_manager.robot_d,
+        _ => panic!("Invalid Robot ID: {}", id),
+      };
+      // Same safety net as a single move, extended by the trajectory length
+      let timeout_ms = 1000 * 60 + robot.trajectory_time_ms(&trajectory).await;
+      tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(timeout_ms)).await;
+        eprintln!("Trajectory timeout reached. Exiting...");
+        std::process::exit(1);
+      });
+      robot.run_trajectory(&trajectory).await;
+    }
+    "sync" => {
+      if id != "all" || args.len() < 6 {
+        eprintln!("Usage: cargo run robots all sync <pos> <rpm>");
+        return;
+      }
+      let pos: f64 = args[4].parse().expect("Invalid position value");
+      let rpm: f64 = args[5].parse().expect("Invalid rpm value");
+      println!("Synchronized move of all robots to {} at {} RPM", pos, rpm);
+      tokio::spawn(async {
+        let move_seconds = 60;
+        tokio::time::sleep(Duration::from_secs(move_seconds)).await;
+        eprintln!("Move timeout reached. Exiting...");
+        std::process::exit(1);
+      });
+      let robot_manager = robots::RobotManager::new().await;
+      let targets = [
+        (Arc::clone(&robot_manager.robot_a), pos),
+        (Arc::clone(&robot_manager.robot_b), pos),
+        (Arc::clone(&robot_manager.robot_c), pos),
+        (Arc::clone(&robot_manager.robot_d), pos),
+      ];
+      robot_manager
+        .sync_move(&targets, rpm)
+        .await
+        .expect("Synchronized move failed");
+    }
+    "coordinate" => {
+      if id != "all" || args.len() < 6 {
+        eprintln!(
+          "Usage: cargo run robots all coordinate <pos> <seconds> [<offset_seconds>]"
+        );
+        return;
+      }
+      let pos: f64 = args[4].parse().expect("Invalid position value");
+      let seconds: f64 = args[5].parse().expect("Invalid duration value");
+      let offset: f64 = match args.get(6) {
+        Some(offset) => offset.parse().expect("Invalid offset value"),
+        None => 0.0,
+      };
+      println!(
+        "Coordinated move of all robots to {} in {} s, offset {} s",
+        pos, seconds, offset
+      );
+      let duration_ms = (seconds * 1000.0) as u64;
+      let offset_ms = (offset * 1000.0) as u64;
+      let timeout_ms = 1000 * 60 + duration_ms + 4 * offset_ms;
+      tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(timeout_ms)).await;
+        eprintln!("Move timeout reached. Exiting...");
+        std::process::exit(1);
+      });
+      let robot_manager = robots::RobotManager::new().await;
+      let targets = [
+        (Arc::clone(&robot_manager.robot_a), pos),
+        (Arc::clone(&robot_manager.robot_b), pos),
+        (Arc::clone(&robot_manager.robot_c), pos),
+        (Arc::clone(&robot_manager.robot_d), pos),
+      ];
+      robot_manager
+        .coordinated_move(&targets, duration_ms, offset_ms)
+        .await
+        .expect("Coordinated move failed");
     }
     _ => eprintln!("Unknown robots subcommand: {}", command),
   }
@@ -129,49 +313,177 @@ async fn handle_robots(args: &[String]) {
   std::process::exit(0);
 }
 
+/// Follows a move from the console: `<pos> <speed>` retargets it, `stop` or
+/// Ctrl-C stops the robot.
+async fn drive_move(mut handle: robots::moves::MoveHandle) {
+  println!("Type <pos> <speed> to retarget, stop or Ctrl-C to stop");
+  let mut lines = BufReader::new(tokio::io::stdin()).lines();
+  let mut stdin_open = true;
+  loop {
+    let line = tokio::select! {
+      outcome = handle.wait() => {
+        match outcome {
+          Ok(outcome) => println!("Move ended: {:?}", outcome),
+          Err(e) => eprintln!("Move failed: {}", e),
+        }
+        return;
+      }
+      line = lines.next_line(), if stdin_open => line.ok().flatten(),
+      _ = tokio::signal::ctrl_c() => Some(String::from("stop")),
+    };
+    let Some(line) = line else {
+      stdin_open = false;
+      continue;
+    };
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+      ["stop"] => {
+        if let Err(e) = handle.cancel().await {
+          eprintln!("Cannot stop robot: {}", e);
+        }
+      }
+      [pos, speed] => match (pos.parse(), speed.parse()) {
+        (Ok(pos), Ok(speed)) => {
+          println!("Retargeting to position: {} with speed: {}", pos, speed);
+          handle = handle.retarget(pos, speed);
+        }
+        _ => eprintln!("Invalid position or speed: {}", line),
+      },
+      [] => {}
+      _ => eprintln!("Type <pos> <speed> or stop"),
+    }
+  }
+}
+
 async fn handle_lights(args: &[String]) {
   if args.len() < 4 {
-    eprintln!("Usage: cargo run lights <ID> <ON/OFF>");
+    eprintln!("Usage: cargo run lights <ID> <on/off/dim/fade> [args...]");
+    eprintln!("       cargo run lights all <set/cross> [args...]");
     return;
   }
 
   let id = &args[2];
   let state = &args[3];
 
-  let serial_device: Arc<Mutex<dyn SerialDevice>> =
-    if config::get(config::ConfigParam::DRYRUN) {
-      Arc::new(Mutex::new(
-        utils::MockSerialDevice::new(
-          lights::LIGHT_SERIAL_PORT_NAME,
-          lights::LIGHT_SERIAL_BAUD,
-        )
-        .expect("Cannot initialize MockSerialDevice"),
-      ))
-    } else {
-      let real = utils::RealSerialDevice::new(
-        lights::LIGHT_SERIAL_PORT_NAME,
-        lights::LIGHT_SERIAL_BAUD,
-      )
-      .await
-      .expect("Cannot initialize RealSerialDevice");
-      Arc::new(Mutex::new(real))
-    };
+  if id == "all" {
+    handle_all_lights(args).await;
+    return;
+  }
+
+  let mut drivers = lights::driver::Drivers::new();
   match state.as_str() {
     "on" => {
       println!("Turning light {} for ID: {}", state, id);
-      let mut light = lights::create(id, Arc::clone(&serial_device)).await;
+      let light = lights::create(id, &mut drivers).await;
       light.turn_on();
     }
     "off" => {
       println!("Turning light {} for ID: {}", state, id);
-      let mut light = lights::create(id, Arc::clone(&serial_device)).await;
+      let light = lights::create(id, &mut drivers).await;
       light.turn_off();
     }
+    "dim" => {
+      if args.len() < 5 {
+        eprintln!("Usage: cargo run lights <ID> dim <level>");
+        return;
+      }
+      let level: u16 = args[4].parse().expect("Invalid level value");
+      println!("Dimming light ID: {} to {}", id, level);
+      let light = lights::create(id, &mut drivers).await;
+      light.dim(level);
+    }
+    "fade" => {
+      if args.len() < 6 {
+        eprintln!(
+          "Usage: cargo run lights <ID> fade <level> <seconds> [<easing>]"
+        );
+        return;
+      }
+      let level: u16 = args[4].parse().expect("Invalid level value");
+      let seconds: f64 = args[5].parse().expect("Invalid duration value");
+      let easing: lights::fade::Easing = match args.get(6) {
+        Some(easing) => easing.parse().expect("Invalid easing"),
+        None => lights::fade::Easing::default(),
+      };
+      println!(
+        "Fading light ID: {} to {} in {} s ({:?})",
+        id, level, seconds, easing
+      );
+      let light = lights::create(id, &mut drivers).await;
+      lights::fade::run(
+        Arc::new(light),
+        level,
+        (seconds * 1000.0) as u64,
+        easing,
+        show::get().lights.fade_rate_hz,
+      )
+      .await;
+    }
     _ => eprintln!("Invalid state for light: {}", state),
   }
 }
 
+async fn handle_all_lights(args: &[String]) {
+  let light_manager = lights::LightManager::connect().await;
+  match args[3].as_str() {
+    "set" => {
+      if args.len() < 10 {
+        eprintln!("Usage: cargo run lights all set <A> <B> <C> <D> <E> <F>");
+        return;
+      }
+      let mut levels = [0u16; 6];
+      for (level, arg) in levels.iter_mut().zip(&args[4..10]) {
+        *level = arg.parse().expect("Invalid level value");
+      }
+      println!("Setting all lights to {:?}", levels);
+      light_manager.set_all(levels).await;
+    }
+    "cross" => {
+      if args.len() < 8 {
+        eprintln!(
+          "Usage: cargo run lights all cross <FROM> <TO> <level> <seconds> [<easing>]"
+        );
+        return;
+      }
+      let group = |names: &str| {
+        names
+          .split(',')
+          .map(|name| {
+            light_manager
+              .light(name)
+              .unwrap_or_else(|| panic!("Invalid light name: {}", name))
+          })
+          .collect::<Vec<_>>()
+      };
+      let from = group(&args[4]);
+      let to = group(&args[5]);
+      let level: u16 = args[6].parse().expect("Invalid level value");
+      let seconds: f64 = args[7].parse().expect("Invalid duration value");
+      let easing: lights::fade::Easing = match args.get(8) {
+        Some(easing) => easing.parse().expect("Invalid easing"),
+        None => lights::fade::Easing::default(),
+      };
+      println!(
+        "Cross-fading lights {} to {} at {} in {} s ({:?})",
+        args[4], args[5], level, seconds, easing
+      );
+      light_manager
+        .cross_fade(&from, &to, level, (seconds * 1000.0) as u64, easing)
+        .await
+        .expect("Cross-fade panicked");
+    }
+    _ => eprintln!("Unknown lights all subcommand: {}", args[3]),
+  }
+}
+
 async fn handle_sparklings(args: &[String]) {
+  if args.get(2).map(|arg| arg.as_str()) == Some("status") {
+    match sparklings::query().await {
+      Ok(states) => println!("Sparkling states: {:?}", states),
+      Err(e) => eprintln!("Cannot read sparkling states: {}", e),
+    }
+    return;
+  }
   if args.len() < 4 {
     eprintln!("Usage: cargo run sparklings <ID> <ON/OFF>");
     return;
@@ -195,6 +507,246 @@ async fn handle_sparklings(args: &[String]) {
   }
 }
 
+async fn handle_selftest(args: &[String]) {
+  let report = selftest::run().await;
+  report.print();
+  if let Some(path) = args.get(2) {
+    report.write(path).expect("Cannot write selftest report");
+    println!("Selftest report written to {}", path);
+  }
+  if !report.passed {
+    std::process::exit(1);
+  }
+}
+
+fn handle_journal(args: &[String]) {
+  let mut query = journal::Query {
+    limit: Some(50),
+    ..Default::default()
+  };
+  let mut json = false;
+  let mut options = args[2..].iter();
+  while let Some(option) = options.next() {
+    match option.as_str() {
+      "--failed" => query.failed = true,
+      "--json" => json = true,
+      "--all" => query.limit = None,
+      "--since" | "--until" | "--device" | "--origin" | "--limit" => {
+        let Some(value) = options.next() else {
+          eprintln!("Missing value for {}", option);
+          return;
+        };
+        match option.as_str() {
+          "--since" => {
+            query.since =
+              Some(journal::parse_time(value).expect("Invalid --since"))
+          }
+          "--until" => {
+            query.until =
+              Some(journal::parse_time(value).expect("Invalid --until"))
+          }
+          "--device" => query.device = Some(value.clone()),
+          "--origin" => {
+            query.origin = Some(
+              serde_json::from_value(serde_json::json!(value))
+                .expect("Invalid origin, use scene, cli, watchdog, api, osc, keyboard or input"),
+            )
+          }
+          _ => query.limit = Some(value.parse().expect("Invalid limit")),
+        }
+      }
+      _ => {
+        eprintln!(
+          "Usage: cargo run journal [--since <when>] [--until <when>] [--device <name>] [--origin <scene/cli/watchdog/api/osc/keyboard/input>] [--failed] [--limit <n>/--all] [--json]"
+        );
+        return;
+      }
+    }
+  }
+  for entry in journal::query(&query) {
+    if json {
+      println!("{}", serde_json::to_string(&entry).unwrap());
+    } else {
+      journal::print(&entry);
+    }
+  }
+}
+
+fn handle_schedule(args: &[String]) {
+  let usage = "Usage: cargo run schedule [open/closed [--until <when>]/auto]";
+  match args.get(2).map(|arg| arg.as_str()) {
+    None => {
+      let config = &show::get().schedule;
+      let now = chrono::Local::now().naive_local();
+      if !config.enabled {
+        println!("Schedule disabled, the installation runs when started");
+      }
+      match config.hours_on(now.date()) {
+        Some(hours) => println!("Today open {} - {}", hours.open, hours.close),
+        None => println!("Today closed"),
+      }
+      println!("Scheduled now: {:?}", config.period_at(now));
+      if let Some((at, period)) = config.next_change(now) {
+        println!("Next: {:?} at {}", period, at);
+      }
+      match schedule::get_override() {
+        Some(manual) => println!(
+          "Override: {:?} until {}",
+          manual.period,
+          manual
+            .until
+            .map(|until| until.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or(String::from("reset"))
+        ),
+        None => println!("Override: none"),
+      }
+    }
+    Some("auto") => {
+      schedule::set_override(None).expect("Cannot reset override");
+      println!("Following the schedule");
+    }
+    Some(mode @ ("open" | "closed")) => {
+      let until = match (args.get(3).map(|arg| arg.as_str()), args.get(4)) {
+        (Some("--until"), Some(value)) => {
+          Some(schedule::parse_until(value).expect("Invalid --until"))
+        }
+        (None, _) => None,
+        _ => {
+          eprintln!("{}", usage);
+          return;
+        }
+      };
+      let period = if mode == "open" {
+        schedule::Period::Open
+      } else {
+        schedule::Period::Closed
+      };
+      schedule::set_override(Some(schedule::Override { period, until }))
+        .expect("Cannot write override");
+      println!("Override: {:?}", period);
+    }
+    Some(_) => eprintln!("{}", usage),
+  }
+}
+
+fn handle_params(args: &[String]) {
+  let usage = "Usage: cargo run params [set <name> <value>/reset [<name>]]";
+  let result = match (args.get(2).map(|arg| arg.as_str()), args.get(3)) {
+    (None, _) => params::list().map(|list| {
+      for (name, value, source) in list {
+        let source = format!("{:?}", source).to_lowercase();
+        println!("{:<24} {:<10} {}", name, value, source);
+      }
+    }),
+    (Some("set"), Some(name)) => match args.get(4) {
+      Some(value) => {
+        params::set(name, value).map(|_| println!("{} set to {}", name, value))
+      }
+      None => {
+        eprintln!("{}", usage);
+        return;
+      }
+    },
+    (Some("reset"), name) => params::reset(name.map(|name| name.as_str()))
+      .map(|_| println!("Back to the show file values")),
+    _ => {
+      eprintln!("{}", usage);
+      return;
+    }
+  };
+  if let Err(e) = result {
+    eprintln!("Cannot change parameters: {}", e);
+  }
+}
+
+async fn handle_estop(args: &[String]) {
+  match args.get(2).map(|arg| arg.as_str()) {
+    Some("reset") => match estop::reset(estop::Source::Cli) {
+      Ok(()) => println!("Emergency stop released"),
+      Err(e) => eprintln!("Cannot release emergency stop: {}", e),
+    },
+    Some("status") => match estop::latched() {
+      Some(latch) => println!(
+        "Emergency stop latched since {} by {:?}{}: {}",
+        latch.since.format("%Y-%m-%d %H:%M:%S"),
+        latch.source,
+        if latch.stopped {
+          ""
+        } else {
+          ", motors NOT confirmed stopped"
+        },
+        latch.reason.unwrap_or_default()
+      ),
+      None => println!("Emergency stop not latched"),
+    },
+    _ => {
+      let reason = (args.len() > 2).then(|| args[2..].join(" "));
+      estop::trigger(estop::Source::Cli, reason)
+        .expect("Cannot latch emergency stop");
+      let robot_manager = robots::RobotManager::new().await;
+      let sparklings: Vec<Arc<sparklings::Sparkling>> = ["1", "2", "3"]
+        .iter()
+        .map(|id| Arc::new(sparklings::create(id)))
+        .collect();
+      // The dimmer is held by a running installation, which sets the lights
+      let lights = match tokio::spawn(lights::LightManager::connect()).await {
+        Ok(light_manager) => light_manager.lights(),
+        Err(_) => {
+          eprintln!("Cannot open the lights, left to the installation");
+          vec![]
+        }
+      };
+      let result =
+        estop::halt(&robot_manager.robots(), &lights, &sparklings).await;
+      match result {
+        Ok(()) => {
+          estop::confirm_stopped().expect("Cannot record the motor stop")
+        }
+        // The bus is held by a running installation, which stops them
+        Err(e) => {
+          if !estop::wait_stopped(1000 * 3).await {
+            eprintln!("Emergency stop NOT completed: {}", e);
+            std::process::exit(1);
+          }
+        }
+      }
+      println!("Emergency stop latched, release with: estop reset");
+    }
+  }
+  std::process::exit(0);
+}
+
+async fn handle_inputs() {
+  let config = &show::get().inputs;
+  let levels = match inputs::query().await {
+    Ok(levels) => levels,
+    Err(e) => {
+      eprintln!("Cannot read inputs: {}", e);
+      return;
+    }
+  };
+  let mut pins: Vec<_> = levels.into_iter().collect();
+  pins.sort();
+  for (pin, level) in pins {
+    let level_name = if level { "HIGH" } else { "LOW" };
+    match config.pins.get(&pin) {
+      Some(input) => println!(
+        "{} {} {} {:?}{}",
+        pin,
+        level_name,
+        input.name(&pin),
+        input.action,
+        if input.is_active(level) {
+          " ACTIVE"
+        } else {
+          ""
+        }
+      ),
+      None => println!("{} {}", pin, level_name),
+    }
+  }
+}
+
 async fn handle_installation(args: &[String]) {
   if args.len() < 3 {
     eprintln!("Usage: cargo run installation <start/stop>");
@@ -0,0 +1,288 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::utils;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+  Counter,
+  Gauge,
+}
+
+pub struct Metric {
+  pub name: &'static str,
+  pub kind: Kind,
+  pub help: &'static str,
+}
+
+pub const MOVES_COMMANDED: Metric = Metric {
+  name: "director_moves_commanded_total",
+  kind: Kind::Counter,
+  help: "Moves sent to a robot",
+};
+pub const MOVES_COMPLETED: Metric = Metric {
+  name: "director_moves_completed_total",
+  kind: Kind::Counter,
+  help: "Moves a robot finished",
+};
+pub const MOVES_TIMED_OUT: Metric = Metric {
+  name: "director_moves_timed_out_total",
+  kind: Kind::Counter,
+  help: "Moves whose motor never reported in position",
+};
+pub const MOVES_CANCELLED: Metric = Metric {
+  name: "director_moves_cancelled_total",
+  kind: Kind::Counter,
+  help: "Moves cancelled or pre-empted before reaching their target",
+};
+pub const MOVE_SECONDS: Metric = Metric {
+  name: "director_move_seconds_total",
+  kind: Kind::Counter,
+  help: "Time spent in completed moves",
+};
+pub const MOVE_PREDICTED_SECONDS: Metric = Metric {
+  name: "director_move_predicted_seconds_total",
+  kind: Kind::Counter,
+  help: "Predicted time of completed moves",
+};
+pub const MOVE_OVERRUN_RATIO: Metric = Metric {
+  name: "director_move_overrun_ratio",
+  kind: Kind::Gauge,
+  help: "Duration of the last move over its predicted time",
+};
+pub const POSITION_ERROR: Metric = Metric {
+  name: "director_position_error",
+  kind: Kind::Gauge,
+  help: "Distance between the real and the tracked position before a move",
+};
+pub const BUS_VOLTAGE: Metric = Metric {
+  name: "director_bus_voltage_volts",
+  kind: Kind::Gauge,
+  help: "Supply voltage seen by the motor driver",
+};
+pub const FAILURES: Metric = Metric {
+  name: "director_failures_total",
+  kind: Kind::Counter,
+  help: "Failed script, serial and HTTP calls",
+};
+pub const SPARKLING_ON: Metric = Metric {
+  name: "director_sparkling_on",
+  kind: Kind::Gauge,
+  help: "Whether the relay is on, as last confirmed",
+};
+pub const SPARKLING_ON_SECONDS: Metric = Metric {
+  name: "director_sparkling_on_seconds_total",
+  kind: Kind::Counter,
+  help: "Time the relay was on",
+};
+pub const INPUT_ACTIVE: Metric = Metric {
+  name: "director_input_active",
+  kind: Kind::Gauge,
+  help: "Whether a Controllino input is active",
+};
+pub const PHASE: Metric = Metric {
+  name: "director_phase",
+  kind: Kind::Gauge,
+  help: "1 for the current installation phase",
+};
+pub const SCHEDULE_PERIOD: Metric = Metric {
+  name: "director_schedule_period",
+  kind: Kind::Gauge,
+  help: "1 for the current period of the opening hours",
+};
+pub const PRESENCE: Metric = Metric {
+  name: "director_presence",
+  kind: Kind::Gauge,
+  help: "1 for whether anybody is around: present, empty or idle",
+};
+pub const PHASE_SECONDS: Metric = Metric {
+  name: "director_phase_seconds_total",
+  kind: Kind::Counter,
+  help: "Time spent in each installation phase",
+};
+pub const PHASES: Metric = Metric {
+  name: "director_phases_total",
+  kind: Kind::Counter,
+  help: "Installation phases run",
+};
+pub const UPTIME: Metric = Metric {
+  name: "director_uptime_seconds",
+  kind: Kind::Gauge,
+  help: "Time since the director started",
+};
+
+/// `metrics` section of the show file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+  pub enabled: bool,
+  pub address: String,
+}
+
+impl Default for MetricsConfig {
+  fn default() -> Self {
+    MetricsConfig {
+      enabled: true,
+      address: String::from("127.0.0.1:9464"),
+    }
+  }
+}
+
+struct Family {
+  metric: &'static Metric,
+  /// Value by rendered label set, e.g. `robot="A"`
+  series: BTreeMap<String, f64>,
+}
+
+static REGISTRY: Mutex<BTreeMap<&'static str, Family>> =
+  Mutex::new(BTreeMap::new());
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+fn update(
+  metric: &'static Metric,
+  labels: &[(&str, &str)],
+  op: impl FnOnce(&mut f64),
+) {
+  let labels = labels
+    .iter()
+    .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+    .collect::<Vec<_>>()
+    .join(",");
+  let mut registry = REGISTRY.lock().unwrap();
+  let family = registry.entry(metric.name).or_insert_with(|| Family {
+    metric,
+    series: BTreeMap::new(),
+  });
+  op(family.series.entry(labels).or_insert(0.0));
+}
+
+pub fn inc(metric: &'static Metric, labels: &[(&str, &str)]) {
+  add(metric, labels, 1.0);
+}
+
+pub fn add(metric: &'static Metric, labels: &[(&str, &str)], value: f64) {
+  update(metric, labels, |current| *current += value);
+}
+
+pub fn set(metric: &'static Metric, labels: &[(&str, &str)], value: f64) {
+  update(metric, labels, |current| *current = value);
+}
+
+pub fn failure(kind: &str) {
+  inc(&FAILURES, &[("kind", kind)]);
+}
+
+/// Records a finished move of `robot` against the time it was expected to
+/// take.
+pub fn move_completed(robot: &str, elapsed: Duration, predicted_ms: u64) {
+  let labels = [("robot", robot)];
+  inc(&MOVES_COMPLETED, &labels);
+  add(&MOVE_SECONDS, &labels, elapsed.as_secs_f64());
+  add(
+    &MOVE_PREDICTED_SECONDS,
+    &labels,
+    predicted_ms as f64 / 1000.0,
+  );
+  if predicted_ms > 0 {
+    let ratio = elapsed.as_millis() as f64 / predicted_ms as f64;
+    set(&MOVE_OVERRUN_RATIO, &labels, ratio);
+  }
+}
+
+/// Marks `phase` as the current one, returns when it started.
+pub fn enter_phase(phase: &str) -> Instant {
+  set_current(&PHASE, "phase", phase);
+  Instant::now()
+}
+
+pub fn enter_period(period: &str) {
+  set_current(&SCHEDULE_PERIOD, "period", period);
+}
+
+pub fn enter_presence(occupancy: &str) {
+  set_current(&PRESENCE, "occupancy", occupancy);
+}
+
+/// Sets `metric` to 1 for `value` and to 0 for the values seen before.
+fn set_current(metric: &'static Metric, label: &str, value: &str) {
+  {
+    let mut registry = REGISTRY.lock().unwrap();
+    if let Some(family) = registry.get_mut(metric.name) {
+      family.series.values_mut().for_each(|value| *value = 0.0);
+    }
+  }
+  set(metric, &[(label, value)], 1.0);
+}
+
+pub fn exit_phase(phase: &str, started: Instant) {
+  let labels = [("phase", phase)];
+  inc(&PHASES, &labels);
+  add(&PHASE_SECONDS, &labels, started.elapsed().as_secs_f64());
+}
+
+/// Prometheus text exposition of every metric recorded so far.
+pub fn render() -> String {
+  if let Some(started) = STARTED.get() {
+    set(&UPTIME, &[], started.elapsed().as_secs_f64());
+  }
+  let registry = REGISTRY.lock().unwrap();
+  let mut out = String::new();
+  for family in registry.values() {
+    let kind = match family.metric.kind {
+      Kind::Counter => "counter",
+      Kind::Gauge => "gauge",
+    };
+    let _ =
+      writeln!(out, "# HELP {} {}", family.metric.name, family.metric.help);
+    let _ = writeln!(out, "# TYPE {} {}", family.metric.name, kind);
+    for (labels, value) in &family.series {
+      if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", family.metric.name, value);
+      } else {
+        let _ = writeln!(out, "{}{{{}}} {}", family.metric.name, labels, value);
+      }
+    }
+  }
+  out
+}
+
+/// Serves `GET /metrics` on `address` until the process exits.
+pub async fn serve(address: &str) -> anyhow::Result<()> {
+  STARTED.get_or_init(Instant::now);
+  let listener = TcpListener::bind(address).await?;
+  tracing::info!(address, "Serving metrics on /metrics");
+  loop {
+    let (stream, _) = listener.accept().await?;
+    tokio::spawn(async move {
+      if let Err(e) = respond(stream).await {
+        tracing::debug!(error = %e, "Metrics request failed");
+      }
+    });
+  }
+}
+
+async fn respond(mut stream: TcpStream) -> anyhow::Result<()> {
+  let (method, path) = utils::read_http_request(&mut stream).await?;
+  let (status, body) = match (method.as_str(), path.as_str()) {
+    ("GET", "/metrics") => ("200 OK", render()),
+    _ => ("404 Not Found", String::from("Not found\n")),
+  };
+  utils::write_http_response(
+    &mut stream,
+    status,
+    "text/plain; version=0.0.4",
+    &body,
+  )
+  .await
+}
+
+fn escape(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
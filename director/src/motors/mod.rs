@@ -0,0 +1,341 @@
+use serde::Serialize;
+use serialport::TTYPort;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::{self, ConfigParam};
+use crate::metrics;
+
+// Native driver for the ZDT Y42 (firmware X) "free protocol", the same one
+// spoken by `new-robots/base/cable_robot.py`.
+// Frames: [addr, code, ..., 0x6B], big-endian multi-byte fields.
+
+pub const MOTOR_SERIAL_PORT_NAME: &str = "/dev/ttyUSB0";
+pub const MOTOR_SERIAL_BAUD: u32 = 115200;
+const MOTOR_SERIAL_TIMEOUT_MS: u64 = 200;
+
+const CHECKSUM: u8 = 0x6B;
+const BROADCAST_ADDRESS: u8 = 0x00;
+
+const CODE_TARGET: u8 = 0xFD;
+const CODE_STATUS: u8 = 0x3A;
+const CODE_BUS_VOLTAGE: u8 = 0x24;
+const CODE_SYNC_TRIGGER: u8 = 0xFF;
+const CODE_STOP: u8 = 0xFE;
+
+const MAX_SPEED_RPM: f64 = 1500.0;
+const MAX_ACC_RPMS: f64 = 150.0;
+pub const DEFAULT_ACC_RPMS: f64 = 100.0;
+
+const GEARBOX_RATIO: f64 = 70.0;
+const DRUM_CIRCUMFERENCE_MM: f64 = 769.5;
+/// Cable length matching a mapped robot position of 1.0
+const MOTOR_TRAVEL_MM: f64 = 5496.0;
+
+pub trait MotorPort: Send {
+  fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()>;
+  fn read_frame(&mut self, len: usize) -> anyhow::Result<Vec<u8>>;
+}
+
+pub struct RealMotorPort {
+  port: TTYPort,
+}
+
+impl RealMotorPort {
+  pub fn new(port_name: &str, baud_rate: u32) -> anyhow::Result<Self> {
+    let port = serialport::new(port_name, baud_rate)
+      .timeout(Duration::from_millis(MOTOR_SERIAL_TIMEOUT_MS))
+      .open_native()?;
+    Ok(RealMotorPort { port })
+  }
+}
+
+impl MotorPort for RealMotorPort {
+  fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+    self.port.write_all(frame)?;
+    self.port.flush()?;
+    // Dongle turn-around margin
+    std::thread::sleep(Duration::from_millis(3));
+    Ok(())
+  }
+
+  fn read_frame(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    self.port.read_exact(&mut buf)?;
+    Ok(buf)
+  }
+}
+
+/// Prints every frame and answers reads with a well formed acknowledgement
+/// for the last frame written.
+pub struct MockMotorPort {
+  last_frame: Vec<u8>,
+}
+
+impl MockMotorPort {
+  pub fn new(port_name: &str, baud_rate: u32) -> anyhow::Result<Self> {
+    tracing::info!("Initialized MockMotorPort {} {}", port_name, baud_rate);
+    Ok(MockMotorPort { last_frame: vec![] })
+  }
+}
+
+impl MotorPort for MockMotorPort {
+  fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+    tracing::info!("[MOCK] Frame sent to motor bus: {}", to_hex(frame));
+    self.last_frame = frame.to_vec();
+    Ok(())
+  }
+
+  fn read_frame(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len.max(3)];
+    buf[0] = *self.last_frame.first().unwrap_or(&0);
+    buf[1] = *self.last_frame.get(1).unwrap_or(&0);
+    buf[2] = 0x02;
+    match buf[1] {
+      // Enabled and in position
+      CODE_STATUS => buf[2] = 0x03,
+      CODE_BUS_VOLTAGE if len == 5 => {
+        buf[2..4].copy_from_slice(&24000u16.to_be_bytes())
+      }
+      _ => {}
+    }
+    buf[len.max(3) - 1] = CHECKSUM;
+    Ok(buf)
+  }
+}
+
+pub struct MotorBus {
+  port: Box<dyn MotorPort>,
+}
+
+/// The bus of this process. The port is opened exclusively, so a stop goes
+/// out on the handle a running move already holds, between its frames.
+static BUS: Mutex<Option<MotorBus>> = Mutex::new(None);
+
+/// Runs `op` on the shared bus, opened on first use and kept open.
+pub fn with_bus<T>(
+  op: impl FnOnce(&mut MotorBus) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+  let mut bus = BUS.lock().unwrap();
+  if bus.is_none() {
+    *bus = Some(MotorBus::open()?);
+  }
+  op(bus.as_mut().unwrap())
+}
+
+/// Closes the shared bus, for the Python scripts opening the port
+/// themselves.
+pub fn release_bus() {
+  BUS.lock().unwrap().take();
+}
+
+impl MotorBus {
+  /// Opens the RS-485 bus, or a mock one in dry run.
+  pub fn open() -> anyhow::Result<Self> {
+    let port: Box<dyn MotorPort> = if config::get(ConfigParam::DRYRUN) {
+      Box::new(MockMotorPort::new(
+        MOTOR_SERIAL_PORT_NAME,
+        MOTOR_SERIAL_BAUD,
+      )?)
+    } else {
+      Box::new(RealMotorPort::new(
+        MOTOR_SERIAL_PORT_NAME,
+        MOTOR_SERIAL_BAUD,
+      )?)
+    };
+    Ok(MotorBus { port })
+  }
+
+  /// Quick ping via the status command.
+  pub fn probe(&mut self, address: u8) -> bool {
+    if self
+      .port
+      .write_frame(&[address, CODE_STATUS, CHECKSUM])
+      .is_err()
+    {
+      return false;
+    }
+    match self.port.read_frame(4) {
+      Ok(resp) => {
+        resp[0] == address && resp[1] == CODE_STATUS && resp[3] == CHECKSUM
+      }
+      Err(_) => false,
+    }
+  }
+
+  /// Supply voltage seen by the driver, in millivolts.
+  pub fn bus_voltage_mv(&mut self, address: u8) -> anyhow::Result<u16> {
+    self
+      .port
+      .write_frame(&[address, CODE_BUS_VOLTAGE, CHECKSUM])?;
+    let resp = self.port.read_frame(5)?;
+    if resp[0] != address || resp[1] != CODE_BUS_VOLTAGE || resp[4] != CHECKSUM
+    {
+      anyhow::bail!("Unexpected bus voltage reply {}", to_hex(&resp));
+    }
+    Ok(u16::from_be_bytes([resp[2], resp[3]]))
+  }
+
+  pub fn status(&mut self, address: u8) -> anyhow::Result<MotorStatus> {
+    self.port.write_frame(&[address, CODE_STATUS, CHECKSUM])?;
+    let resp = self.port.read_frame(4)?;
+    if resp[0] != address || resp[1] != CODE_STATUS || resp[3] != CHECKSUM {
+      anyhow::bail!("Unexpected status reply {}", to_hex(&resp));
+    }
+    let flags = resp[2];
+    Ok(MotorStatus {
+      enabled: flags & 0x01 != 0,
+      in_position: flags & 0x02 != 0,
+      stalled: flags & 0x04 != 0,
+      stall_protection: flags & 0x08 != 0,
+    })
+  }
+
+  /// Absolute trapezoidal move. With `sync` the motor stores the target and
+  /// waits for `sync_trigger` instead of starting right away.
+  pub fn set_target(
+    &mut self,
+    address: u8,
+    position_deg: f64,
+    speed_rpm: f64,
+    acc_rpms: f64,
+    sync: bool,
+  ) -> anyhow::Result<()> {
+    let frame = target_frame(address, position_deg, speed_rpm, acc_rpms, sync);
+    self.port.write_frame(&frame)?;
+    if !self.expect_ack(address, CODE_TARGET) && !self.probe(address) {
+      metrics::failure("serial");
+      anyhow::bail!(
+        "Motor {} did not ACK and is not responding to status",
+        address
+      );
+    }
+    Ok(())
+  }
+
+  /// Stops the motor where it is, dropping its target.
+  pub fn stop(&mut self, address: u8) -> anyhow::Result<()> {
+    self
+      .port
+      .write_frame(&[address, CODE_STOP, 0x98, 0x00, CHECKSUM])?;
+    if !self.expect_ack(address, CODE_STOP) {
+      metrics::failure("serial");
+      anyhow::bail!("Motor {} did not ACK stop", address);
+    }
+    Ok(())
+  }
+
+  /// Starts every move staged with the sync flag, on all motors at once.
+  pub fn sync_trigger(&mut self) -> anyhow::Result<()> {
+    // Broadcast frames are never acknowledged
+    self.port.write_frame(&[
+      BROADCAST_ADDRESS,
+      CODE_SYNC_TRIGGER,
+      0x66,
+      CHECKSUM,
+    ])
+  }
+
+  fn expect_ack(&mut self, address: u8, code: u8) -> bool {
+    match self.port.read_frame(4) {
+      Ok(resp) => {
+        resp[0] == address
+          && resp[1] == code
+          && resp[3] == CHECKSUM
+          && (resp[2] == 0x02 || resp[2] == 0x9F)
+      }
+      Err(_) => false,
+    }
+  }
+}
+
+/// Status flags of a motor (0x3A).
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MotorStatus {
+  pub enabled: bool,
+  pub in_position: bool,
+  pub stalled: bool,
+  pub stall_protection: bool,
+}
+
+fn target_frame(
+  address: u8,
+  position_deg: f64,
+  speed_rpm: f64,
+  acc_rpms: f64,
+  sync: bool,
+) -> Vec<u8> {
+  let position_deg = position_deg.max(0.0);
+  let speed_rpm = speed_rpm.clamp(0.0, MAX_SPEED_RPM);
+  let acc = acc_rpms.clamp(0.0, MAX_ACC_RPMS).round() as u16;
+  let vmax = (speed_rpm * 10.0).round() as u16;
+  let position = (position_deg * 10.0).round() as u32;
+  let mut frame = vec![address, CODE_TARGET, 0x00];
+  frame.extend_from_slice(&acc.to_be_bytes());
+  frame.extend_from_slice(&acc.to_be_bytes());
+  frame.extend_from_slice(&vmax.to_be_bytes());
+  frame.extend_from_slice(&position.to_be_bytes());
+  // Absolute motion mode, then the sync flag
+  frame.extend_from_slice(&[0x01, sync as u8, CHECKSUM]);
+  frame
+}
+
+/// Mapped robot position (0.0-1.0) to absolute motor shaft degrees.
+pub fn position_to_deg(mapped_position: f64) -> f64 {
+  let distance_mm = mapped_position * MOTOR_TRAVEL_MM;
+  (distance_mm / DRUM_CIRCUMFERENCE_MM) * 360.0 * GEARBOX_RATIO
+}
+
+/// Duration of a symmetric accel/decel trapezoid covering `distance_deg`.
+pub fn time_for_trapezoid_ms(
+  distance_deg: f64,
+  speed_rpm: f64,
+  acc_rpms: f64,
+) -> u64 {
+  let v = speed_rpm * 360.0 / 60.0;
+  let a = acc_rpms * 360.0 / 60.0;
+  if v <= 0.0 || a <= 0.0 {
+    return 0;
+  }
+  let t_acc = v / a;
+  let d_accdec = v * v / a;
+  let seconds = if distance_deg >= d_accdec {
+    2.0 * t_acc + (distance_deg - d_accdec) / v
+  } else {
+    // Triangular profile, peak lower than vmax
+    2.0 * (distance_deg / a).sqrt()
+  };
+  (seconds * 1000.0).ceil() as u64
+}
+
+/// Speed and acceleration so that a move of `distance_deg` lasts
+/// `duration_ms`. Keeps the default acceleration when possible, falls back to
+/// a triangular profile otherwise. Both are capped to the device limits, in
+/// which case the move takes longer than asked.
+pub fn solve_profile(distance_deg: f64, duration_ms: u64) -> (f64, f64) {
+  let t = duration_ms as f64 / 1000.0;
+  if distance_deg <= 0.0 || t <= 0.0 {
+    return (0.0, DEFAULT_ACC_RPMS);
+  }
+  let a = DEFAULT_ACC_RPMS * 360.0 / 60.0;
+  // Trapezoid: t = v / a + d / v
+  let discriminant = a * a * t * t - 4.0 * a * distance_deg;
+  let (v, a) = if discriminant >= 0.0 {
+    ((a * t - discriminant.sqrt()) / 2.0, a)
+  } else {
+    let a = 4.0 * distance_deg / (t * t);
+    (a * t / 2.0, a)
+  };
+  let speed_rpm = (v * 60.0 / 360.0).min(MAX_SPEED_RPM);
+  let acc_rpms = (a * 60.0 / 360.0).min(MAX_ACC_RPMS);
+  (speed_rpm, acc_rpms)
+}
+
+fn to_hex(frame: &[u8]) -> String {
+  frame
+    .iter()
+    .map(|b| format!("{:02X}", b))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
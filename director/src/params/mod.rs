@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::show;
+use crate::utils;
+
+/// Pacing of the choreography, read on every cycle. Set from the built-in
+/// defaults, then the `params` section of the show file, then the live
+/// values changed with `director params set`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Params {
+  pub buffering_time_ms: u64,
+  pub scanning_time_ms: u64,
+  pub syncing_time_ms: u64,
+  /// Time the robots take to reach the syncing position together
+  pub syncing_move_time_ms: u64,
+  /// Delay windows, for the delays the show file leaves to the default
+  pub buffering_delay_min_ms: u64,
+  pub buffering_delay_max_ms: u64,
+  pub scanning_delay_min_ms: u64,
+  pub scanning_delay_max_ms: u64,
+  pub syncing_delay_min_ms: u64,
+  pub syncing_delay_max_ms: u64,
+  pub scanning_position: f64,
+  pub syncing_position: f64,
+}
+
+impl Default for Params {
+  fn default() -> Self {
+    Params {
+      buffering_time_ms: 1000 * 60 * 2,
+      scanning_time_ms: 1000 * 60,
+      syncing_time_ms: 1000 * 60,
+      syncing_move_time_ms: 1000 * 30,
+      buffering_delay_min_ms: 1000 * 10,
+      buffering_delay_max_ms: 1000 * 60,
+      scanning_delay_min_ms: 1000 * 10,
+      scanning_delay_max_ms: 1000 * 60,
+      syncing_delay_min_ms: 1000 * 10,
+      syncing_delay_max_ms: 1000 * 60,
+      scanning_position: 4.8,
+      syncing_position: 0.8,
+    }
+  }
+}
+
+impl Params {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    for (name, min, max) in [
+      (
+        "buffering_delay",
+        self.buffering_delay_min_ms,
+        self.buffering_delay_max_ms,
+      ),
+      (
+        "scanning_delay",
+        self.scanning_delay_min_ms,
+        self.scanning_delay_max_ms,
+      ),
+      (
+        "syncing_delay",
+        self.syncing_delay_min_ms,
+        self.syncing_delay_max_ms,
+      ),
+    ] {
+      if min > max {
+        anyhow::bail!("{}_min_ms must be at most {}_max_ms", name, name);
+      }
+    }
+    for (name, position) in [
+      ("scanning_position", self.scanning_position),
+      ("syncing_position", self.syncing_position),
+    ] {
+      if !(0.0..=5.0).contains(&position) {
+        anyhow::bail!("{} must be between 0.0 and 5.0", name);
+      }
+    }
+    Ok(())
+  }
+
+  pub fn buffering_delay(&self) -> (u64, u64) {
+    (self.buffering_delay_min_ms, self.buffering_delay_max_ms)
+  }
+
+  pub fn scanning_delay(&self) -> (u64, u64) {
+    (self.scanning_delay_min_ms, self.scanning_delay_max_ms)
+  }
+
+  pub fn syncing_delay(&self) -> (u64, u64) {
+    (self.syncing_delay_min_ms, self.syncing_delay_max_ms)
+  }
+}
+
+/// Where a parameter value comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+  Default,
+  Show,
+  Live,
+}
+
+/// Live values as last read, with the modification time of their file.
+static LIVE: Mutex<Option<(Option<SystemTime>, Params)>> = Mutex::new(None);
+
+fn live_path() -> PathBuf {
+  utils::get_home_dir().join("triennale-25/params.json")
+}
+
+/// Live values changed with `director params set`, by name.
+fn read_live() -> Map<String, Value> {
+  fs::read_to_string(live_path())
+    .ok()
+    .and_then(|content| serde_json::from_str(&content).ok())
+    .unwrap_or_default()
+}
+
+fn merge(live: &Map<String, Value>) -> anyhow::Result<Params> {
+  let mut value = serde_json::to_value(&show::get().params)?;
+  let fields = value.as_object_mut().unwrap();
+  for (name, live_value) in live {
+    if !fields.contains_key(name) {
+      anyhow::bail!("Unknown parameter {}", name);
+    }
+    fields.insert(name.clone(), live_value.clone());
+  }
+  let params: Params = serde_json::from_value(value)?;
+  params.validate()?;
+  Ok(params)
+}
+
+/// Current parameters. The live file is read again when it changed, an
+/// invalid one is ignored and the previous values kept.
+pub fn get() -> Params {
+  let modified = fs::metadata(live_path())
+    .and_then(|metadata| metadata.modified())
+    .ok();
+  let mut cache = LIVE.lock().unwrap();
+  if let Some((cached_at, params)) = cache.as_ref() {
+    if *cached_at == modified {
+      return params.clone();
+    }
+  }
+  let params = match merge(&read_live()) {
+    Ok(params) => params,
+    Err(e) => {
+      tracing::error!(error = %e, "Invalid live parameters, ignored");
+      cache
+        .as_ref()
+        .map(|(_, params)| params.clone())
+        .unwrap_or_else(|| show::get().params.clone())
+    }
+  };
+  if cache
+    .as_ref()
+    .is_some_and(|(_, previous)| *previous != params)
+  {
+    tracing::info!(params = ?params, "Parameters changed");
+  }
+  *cache = Some((modified, params.clone()));
+  params
+}
+
+/// Every parameter with its value and source.
+pub fn list() -> anyhow::Result<Vec<(String, Value, Source)>> {
+  let live = read_live();
+  let defaults = serde_json::to_value(Params::default())?;
+  let current = serde_json::to_value(merge(&live)?)?;
+  let show = serde_json::to_value(&show::get().params)?;
+  Ok(
+    current
+      .as_object()
+      .unwrap()
+      .iter()
+      .map(|(name, value)| {
+        let source = if live.contains_key(name) {
+          Source::Live
+        } else if show[name] != defaults[name] {
+          Source::Show
+        } else {
+          Source::Default
+        };
+        (name.clone(), value.clone(), source)
+      })
+      .collect(),
+  )
+}
+
+/// Changes a live parameter, kept across restarts.
+pub fn set(name: &str, value: &str) -> anyhow::Result<()> {
+  let mut live = read_live();
+  live.insert(String::from(name), serde_json::from_str(value)?);
+  merge(&live)?;
+  write_live(&live)
+}
+
+/// Back to the show file value, all parameters without `name`.
+pub fn reset(name: Option<&str>) -> anyhow::Result<()> {
+  let mut live = read_live();
+  match name {
+    Some(name) => {
+      if live.remove(name).is_none() {
+        anyhow::bail!("{} has no live value", name);
+      }
+    }
+    None => live.clear(),
+  }
+  write_live(&live)
+}
+
+fn write_live(live: &Map<String, Value>) -> anyhow::Result<()> {
+  let path = live_path();
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  fs::write(path, serde_json::to_string_pretty(live)?)?;
+  Ok(())
+}
@@ -0,0 +1,294 @@
+use rosc::{decoder, OscPacket, OscType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+
+use crate::inputs;
+use crate::installation::Phase;
+use crate::metrics;
+use crate::show;
+use crate::utils;
+
+/// How often the time-based changes (hold expired, idle) are looked for
+const EVALUATE_MS: u64 = 1000;
+
+/// `presence` section of the show file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+  pub enabled: bool,
+  /// Where `/presence [<count>]` is received, none to disable
+  pub osc_address: Option<String>,
+  /// Where plain UDP triggers are received, none to disable
+  pub udp_address: Option<String>,
+  /// How long someone counts as present after the last signal
+  pub hold_ms: u64,
+  /// Phase the show cuts to when someone arrives, none to carry on
+  pub approach_phase: Option<Phase>,
+  /// Speed of the random moves while the room is empty, 1.0 for unchanged
+  pub empty_speed: f64,
+  /// Minutes with nobody around before idling, 0 to never idle
+  pub idle_after_min: u64,
+  /// Level of the lights while idling
+  pub idle_light_level: u16,
+}
+
+impl Default for PresenceConfig {
+  fn default() -> Self {
+    PresenceConfig {
+      enabled: false,
+      osc_address: Some(String::from("0.0.0.0:6668")),
+      udp_address: Some(String::from("0.0.0.0:6669")),
+      hold_ms: 1000 * 30,
+      approach_phase: Some(Phase::Scanning),
+      empty_speed: 0.5,
+      idle_after_min: 10,
+      idle_light_level: 0,
+    }
+  }
+}
+
+impl PresenceConfig {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    if !(self.empty_speed > 0.0 && self.empty_speed <= 1.0) {
+      anyhow::bail!("empty_speed must be over 0.0 and at most 1.0");
+    }
+    Ok(())
+  }
+}
+
+/// Whether anybody is around.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Occupancy {
+  Present,
+  /// Nobody around, for less than `idle_after_min`
+  Empty,
+  /// Nobody around for `idle_after_min` or more
+  Idle,
+}
+
+impl Occupancy {
+  fn name(self) -> &'static str {
+    match self {
+      Occupancy::Present => "PRESENT",
+      Occupancy::Empty => "EMPTY",
+      Occupancy::Idle => "IDLE",
+    }
+  }
+}
+
+/// Last signal of a source: a level stays present until it goes off, a
+/// pulse (`on` false) for `hold_ms` after it came.
+struct Signal {
+  on: bool,
+  at: Instant,
+}
+
+struct Tracker {
+  signals: HashMap<String, Signal>,
+  last_present: Instant,
+  occupancy: Occupancy,
+}
+
+/// `None` while presence is disabled, the show then runs as if someone was
+/// always around.
+static TRACKER: Mutex<Option<Tracker>> = Mutex::new(None);
+static CHANGED: Notify = Notify::const_new();
+
+pub fn current() -> Occupancy {
+  TRACKER
+    .lock()
+    .unwrap()
+    .as_ref()
+    .map_or(Occupancy::Present, |tracker| tracker.occupancy)
+}
+
+/// Factor of the random speeds, slower while the room is empty. The speed
+/// of a move scales its time (see `resolve_time_ms`), so divide by it.
+pub fn speed_factor() -> f64 {
+  match current() {
+    Occupancy::Present => 1.0,
+    _ => show::get().presence.empty_speed,
+  }
+}
+
+/// Waits until nobody was around for `idle_after_min`, or somebody is with
+/// `false`.
+pub async fn wait_idle(idle: bool) {
+  loop {
+    let changed = CHANGED.notified();
+    if (current() == Occupancy::Idle) == idle {
+      return;
+    }
+    changed.await;
+  }
+}
+
+/// A source reporting whether it sees somebody. Only going off counts from
+/// then on, so a source repeating it is off does not keep the room present.
+pub fn report(source: &str, on: bool) {
+  signal(source, on, false);
+}
+
+/// A source seeing somebody without telling when they leave, e.g. a motion
+/// trigger.
+pub fn pulse(source: &str) {
+  signal(source, false, true);
+}
+
+fn signal(source: &str, on: bool, pulse: bool) {
+  {
+    let mut tracker = TRACKER.lock().unwrap();
+    let Some(tracker) = tracker.as_mut() else {
+      tracing::debug!(source, "Presence disabled, signal ignored");
+      return;
+    };
+    let was_on = tracker.signals.get(source).is_some_and(|signal| signal.on);
+    if !on && !pulse && !was_on {
+      return;
+    }
+    tracing::debug!(source, on, "Presence signal");
+    tracker.signals.insert(
+      String::from(source),
+      Signal {
+        on,
+        at: Instant::now(),
+      },
+    );
+  }
+  evaluate();
+}
+
+/// Works out the occupancy and acts on its change: when somebody arrives
+/// in an empty room the show cuts to the approach phase, the director wakes
+/// up by itself from idling.
+fn evaluate() {
+  let config = &show::get().presence;
+  let hold = Duration::from_millis(config.hold_ms);
+  let idle_after = Duration::from_secs(config.idle_after_min * 60);
+  let (from, to) = {
+    let mut tracker = TRACKER.lock().unwrap();
+    let Some(tracker) = tracker.as_mut() else {
+      return;
+    };
+    let present = tracker
+      .signals
+      .values()
+      .any(|signal| signal.on || signal.at.elapsed() < hold);
+    let to = if present {
+      tracker.last_present = Instant::now();
+      Occupancy::Present
+    } else if config.idle_after_min > 0
+      && tracker.last_present.elapsed() >= idle_after
+    {
+      Occupancy::Idle
+    } else {
+      Occupancy::Empty
+    };
+    (std::mem::replace(&mut tracker.occupancy, to), to)
+  };
+  if from == to {
+    return;
+  }
+  tracing::info!(from = from.name(), to = to.name(), "Presence");
+  metrics::enter_presence(to.name());
+  if from == Occupancy::Empty && to == Occupancy::Present {
+    if let Some(phase) = config.approach_phase {
+      inputs::request_scene(phase);
+    }
+  }
+  CHANGED.notify_waiters();
+}
+
+/// Starts tracking presence from the sources of the show file, somebody
+/// counts as present at startup.
+pub fn start(config: &'static PresenceConfig) {
+  if !config.enabled {
+    return;
+  }
+  let signals = HashMap::from([(
+    String::from("start"),
+    Signal {
+      on: false,
+      at: Instant::now(),
+    },
+  )]);
+  *TRACKER.lock().unwrap() = Some(Tracker {
+    signals,
+    last_present: Instant::now(),
+    occupancy: Occupancy::Present,
+  });
+  metrics::enter_presence(Occupancy::Present.name());
+  if let Some(address) = &config.osc_address {
+    tokio::spawn(async move {
+      if let Err(e) = listen_osc(address).await {
+        tracing::error!(error = %e, "Cannot listen for OSC presence");
+      }
+    });
+  }
+  if let Some(address) = &config.udp_address {
+    tokio::spawn(async move {
+      if let Err(e) = listen_udp(address).await {
+        tracing::error!(error = %e, "Cannot listen for UDP presence");
+      }
+    });
+  }
+  tokio::spawn(async {
+    loop {
+      utils::sleep_silent(EVALUATE_MS).await;
+      evaluate();
+    }
+  });
+}
+
+/// Answers `/presence <count>` (or `<true/false>`), a level, and
+/// `/presence` without arguments, a pulse.
+async fn listen_osc(address: &str) -> anyhow::Result<()> {
+  let socket = UdpSocket::bind(address).await?;
+  tracing::info!(address, "Listening for /presence");
+  let mut buf = [0; rosc::decoder::MTU];
+  loop {
+    let (size, _) = socket.recv_from(&mut buf).await?;
+    match decoder::decode_udp(&buf[..size]) {
+      Ok((_, packet)) => handle_packet(packet),
+      Err(e) => tracing::debug!(error = ?e, "Invalid OSC packet"),
+    }
+  }
+}
+
+fn handle_packet(packet: OscPacket) {
+  match packet {
+    OscPacket::Message(msg) => {
+      if msg.addr != "/presence" {
+        return;
+      }
+      match msg.args.first() {
+        Some(OscType::Int(count)) => report("osc", *count > 0),
+        Some(OscType::Float(count)) => report("osc", *count > 0.0),
+        Some(OscType::Bool(on)) => report("osc", *on),
+        _ => pulse("osc"),
+      }
+    }
+    OscPacket::Bundle(bundle) => {
+      bundle.content.into_iter().for_each(handle_packet)
+    }
+  }
+}
+
+/// Answers datagrams: `1` and `0` are a level, anything else a pulse.
+async fn listen_udp(address: &str) -> anyhow::Result<()> {
+  let socket = UdpSocket::bind(address).await?;
+  tracing::info!(address, "Listening for presence triggers");
+  let mut buf = [0; 512];
+  loop {
+    let (size, _) = socket.recv_from(&mut buf).await?;
+    match String::from_utf8_lossy(&buf[..size]).trim() {
+      "1" => report("udp", true),
+      "0" => report("udp", false),
+      _ => pulse("udp"),
+    }
+  }
+}
@@ -0,0 +1,203 @@
+use rand::distributions::WeightedIndex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Beta, Distribution as _, Normal};
+use serde::Deserialize;
+
+use crate::config;
+
+/// A new seed for runs that did not ask for one. It is logged at startup, so
+/// the run can still be replayed with `--seed`.
+pub fn generate_seed() -> u64 {
+  rand::random()
+}
+
+/// Independent random stream for `label`, derived from the director seed.
+/// Every consumer forks its own stream, so the sequence it draws does not
+/// depend on how the tasks interleave.
+pub fn fork(label: &str) -> StdRng {
+  StdRng::seed_from_u64(config::seed() ^ fnv1a(label))
+}
+
+// Stable across builds and platforms, unlike std's DefaultHasher
+fn fnv1a(label: &str) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for byte in label.bytes() {
+    hash ^= byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
+}
+
+/// Named distribution for a choreography parameter, as written in the show
+/// file, e.g. `{ "type": "normal", "mean": 0.5, "std_dev": 0.1 }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Distribution {
+  Uniform {
+    min: f64,
+    max: f64,
+  },
+  /// Normal, clamped to `[min, max]`.
+  Normal {
+    mean: f64,
+    std_dev: f64,
+    min: f64,
+    max: f64,
+  },
+  /// Beta(alpha, beta) scaled from `[0, 1]` to `[min, max]`.
+  Beta {
+    alpha: f64,
+    beta: f64,
+    min: f64,
+    max: f64,
+  },
+  /// One of the preset `values`, picked with the given `weights` (equal
+  /// weights when omitted).
+  Choice {
+    values: Vec<f64>,
+    #[serde(default)]
+    weights: Vec<f64>,
+  },
+  /// Ornstein-Uhlenbeck walk: every draw moves from the previous one towards
+  /// `mean` by `reversion` (0.0-1.0) plus a normal step of `std_dev`, clamped
+  /// to `[min, max]`. Successive values drift instead of jumping.
+  Smooth {
+    mean: f64,
+    std_dev: f64,
+    reversion: f64,
+    min: f64,
+    max: f64,
+  },
+}
+
+impl Distribution {
+  pub fn uniform(min: f64, max: f64) -> Self {
+    Distribution::Uniform { min, max }
+  }
+
+  pub fn validate(&self) -> anyhow::Result<()> {
+    match self {
+      Distribution::Uniform { min, max } => check_range(*min, *max),
+      Distribution::Normal {
+        std_dev, min, max, ..
+      } => {
+        Normal::new(0.0, *std_dev)?;
+        check_range(*min, *max)
+      }
+      Distribution::Beta {
+        alpha,
+        beta,
+        min,
+        max,
+      } => {
+        Beta::new(*alpha, *beta)?;
+        check_range(*min, *max)
+      }
+      Distribution::Choice { values, weights } => {
+        if values.is_empty() {
+          anyhow::bail!("Choice distribution without values");
+        }
+        if !weights.is_empty() {
+          if weights.len() != values.len() {
+            anyhow::bail!("Choice distribution needs one weight per value");
+          }
+          WeightedIndex::new(weights)?;
+        }
+        Ok(())
+      }
+      Distribution::Smooth {
+        std_dev,
+        reversion,
+        min,
+        max,
+        ..
+      } => {
+        Normal::new(0.0, *std_dev)?;
+        if !(0.0..=1.0).contains(reversion) {
+          anyhow::bail!("Smooth distribution reversion must be in 0.0-1.0");
+        }
+        check_range(*min, *max)
+      }
+    }
+  }
+}
+
+fn check_range(min: f64, max: f64) -> anyhow::Result<()> {
+  if min > max {
+    anyhow::bail!("Invalid range {}..{}", min, max);
+  }
+  Ok(())
+}
+
+/// Draws from a `Distribution`, keeping the previous value for the ones
+/// that depend on it.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+  distribution: Distribution,
+  last: Option<f64>,
+}
+
+impl Sampler {
+  pub fn new(distribution: Distribution) -> Self {
+    Sampler {
+      distribution,
+      last: None,
+    }
+  }
+
+  /// Expects a validated distribution.
+  pub fn sample(&mut self, rng: &mut StdRng) -> f64 {
+    let value = match &self.distribution {
+      Distribution::Uniform { min, max } => {
+        if min == max {
+          *min
+        } else {
+          rng.gen_range(*min..*max)
+        }
+      }
+      Distribution::Normal {
+        mean,
+        std_dev,
+        min,
+        max,
+      } => {
+        let normal = Normal::new(*mean, *std_dev).expect("Invalid normal");
+        normal.sample(rng).clamp(*min, *max)
+      }
+      Distribution::Beta {
+        alpha,
+        beta,
+        min,
+        max,
+      } => {
+        let beta = Beta::new(*alpha, *beta).expect("Invalid beta");
+        min + beta.sample(rng) * (max - min)
+      }
+      Distribution::Choice { values, weights } => {
+        let index = if weights.is_empty() {
+          rng.gen_range(0..values.len())
+        } else {
+          WeightedIndex::new(weights)
+            .expect("Invalid weights")
+            .sample(rng)
+        };
+        values[index]
+      }
+      Distribution::Smooth {
+        mean,
+        std_dev,
+        reversion,
+        min,
+        max,
+      } => {
+        let normal = Normal::new(0.0, *std_dev).expect("Invalid normal");
+        let last = self.last.unwrap_or(*mean);
+        (last + reversion * (mean - last) + normal.sample(rng))
+          .clamp(*min, *max)
+      }
+    };
+    self.last = Some(value);
+    value
+  }
+}
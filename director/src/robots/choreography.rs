@@ -0,0 +1,189 @@
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::random::{Distribution, Sampler};
+
+/// Distributions of the parameters a phase draws. Missing ones fall back to
+/// the phase defaults, then to the built-in ones.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PhaseParams {
+  pub position: Option<Distribution>,
+  pub speed: Option<Distribution>,
+  pub delay_ms: Option<Distribution>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PhasesParams {
+  pub buffering: PhaseParams,
+  pub scanning: PhaseParams,
+  pub syncing: PhaseParams,
+}
+
+/// `choreography` section of the show file:
+///
+/// ```json
+/// {
+///   "buffering": { "speed": { "type": "beta", "alpha": 2, "beta": 5,
+///                             "min": 0.1, "max": 0.9 } },
+///   "robots": { "C": { "buffering": { "position": { "type": "choice",
+///                                     "values": [1.0, 2.5, 4.0] } } } }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Choreography {
+  #[serde(flatten)]
+  pub phases: PhasesParams,
+  /// Overrides by robot name
+  pub robots: HashMap<String, PhasesParams>,
+}
+
+impl Choreography {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    let all = std::iter::once(("*", &self.phases))
+      .chain(self.robots.iter().map(|(name, p)| (name.as_str(), p)));
+    for (name, phases) in all {
+      for (phase, params) in [
+        ("buffering", &phases.buffering),
+        ("scanning", &phases.scanning),
+        ("syncing", &phases.syncing),
+      ] {
+        for (param, distribution) in [
+          ("position", &params.position),
+          ("speed", &params.speed),
+          ("delay_ms", &params.delay_ms),
+        ] {
+          if let Some(distribution) = distribution {
+            distribution.validate().map_err(|e| {
+              anyhow::anyhow!(
+                "choreography {} {} {}: {}",
+                name,
+                phase,
+                param,
+                e
+              )
+            })?;
+          }
+        }
+      }
+    }
+    // Syncing moves every robot together to `syncing_position` in
+    // `syncing_move_time_ms` after one shared delay
+    let syncing = &self.phases.syncing;
+    if syncing.position.is_some() || syncing.speed.is_some() {
+      anyhow::bail!(
+        "choreography syncing: only delay_ms applies, the position and move \
+         time are set in params"
+      );
+    }
+    for (name, phases) in &self.robots {
+      let syncing = &phases.syncing;
+      if syncing.position.is_some()
+        || syncing.speed.is_some()
+        || syncing.delay_ms.is_some()
+      {
+        anyhow::bail!(
+          "choreography {} syncing: the robots sync together, set delay_ms \
+           for all of them",
+          name
+        );
+      }
+    }
+    Ok(())
+  }
+
+  fn resolve(
+    &self,
+    robot: &str,
+    select: fn(&PhasesParams) -> &Option<Distribution>,
+    default: Distribution,
+  ) -> Sampler {
+    self
+      .resolve_defined(robot, select)
+      .unwrap_or_else(|| Sampler::new(default))
+  }
+
+  /// Only a distribution the show defines, by robot or by phase.
+  fn resolve_defined(
+    &self,
+    robot: &str,
+    select: fn(&PhasesParams) -> &Option<Distribution>,
+  ) -> Option<Sampler> {
+    self
+      .robots
+      .get(robot)
+      .and_then(|p| select(p).clone())
+      .or_else(|| select(&self.phases).clone())
+      .map(Sampler::new)
+  }
+}
+
+/// Samplers of a single robot, resolved once from the choreography. Delays
+/// and the scanning position the show does not define come from the live
+/// `params`.
+pub struct RobotSamplers {
+  pub buffering_position: Sampler,
+  pub buffering_speed: Sampler,
+  pub buffering_delay: Option<Sampler>,
+  pub scanning_position: Option<Sampler>,
+  pub scanning_speed: Sampler,
+  pub scanning_delay: Option<Sampler>,
+}
+
+impl RobotSamplers {
+  pub fn new(choreography: &Choreography, robot: &str) -> Self {
+    RobotSamplers {
+      buffering_position: choreography.resolve(
+        robot,
+        |p| &p.buffering.position,
+        Distribution::uniform(0.5, 4.5),
+      ),
+      buffering_speed: choreography.resolve(
+        robot,
+        |p| &p.buffering.speed,
+        Distribution::uniform(0.1, 0.9),
+      ),
+      buffering_delay: choreography
+        .resolve_defined(robot, |p| &p.buffering.delay_ms),
+      scanning_position: choreography
+        .resolve_defined(robot, |p| &p.scanning.position),
+      scanning_speed: choreography.resolve(
+        robot,
+        |p| &p.scanning.speed,
+        Distribution::uniform(0.1, 0.9),
+      ),
+      scanning_delay: choreography
+        .resolve_defined(robot, |p| &p.scanning.delay_ms),
+    }
+  }
+}
+
+/// The syncing delay is shared by all robots, so only the phase default
+/// applies.
+pub fn syncing_delay_sampler(choreography: &Choreography) -> Option<Sampler> {
+  choreography
+    .phases
+    .syncing
+    .delay_ms
+    .clone()
+    .map(Sampler::new)
+}
+
+/// Draws a delay from the show's distribution, or uniformly from `window`.
+pub fn sample_delay(
+  sampler: Option<&mut Sampler>,
+  window: (u64, u64),
+  rng: &mut StdRng,
+) -> u64 {
+  let value = match sampler {
+    Some(sampler) => sampler.sample(rng),
+    None => {
+      Sampler::new(Distribution::uniform(window.0 as f64, window.1 as f64))
+        .sample(rng)
+    }
+  };
+  value as u64
+}
@@ -1,28 +1,40 @@
-use rand::Rng;
+use rand::rngs::StdRng;
 use rosc::{encoder, OscMessage, OscPacket, OscType};
-use std::fmt;
+use serde_json::{json, Value};
 use std::fs::{self, File};
 use std::io::{self, Write};
 use std::net::UdpSocket;
 use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, RwLock};
+use tracing::Instrument;
 
 use tokio;
 
 use crate::config::{self, ConfigParam};
+use crate::estop;
+use crate::journal::{self, Outcome};
+use crate::metrics;
+use crate::motors;
+use crate::params;
+use crate::presence;
+use crate::random::{self, Sampler};
+use crate::show;
 use crate::utils;
 
+pub mod choreography;
+pub mod moves;
+pub mod state;
+pub mod trajectory;
+use choreography::RobotSamplers;
+use moves::{Interrupt, MoveHandle, MoveOutcome};
+pub use state::{RobotState, RobotStatus};
+use trajectory::Trajectory;
+
 // const SERVICE_ADDRESS: &'static str = "127.0.0.1:5000";
 const SERVICE_ADDRESS: &'static str = "255.255.255.255:6666";
 
-const SCANNING_POSITION: f64 = 4.8;
-const SYNCING_POSITION: f64 = 0.8;
-
-const BUFFERING_TIME_MS: u64 = 1000 * 60 * 2;
-const SCANNING_TIME_MS: u64 = 1000 * 60 * 1;
-const SYNCING_TIME_MS: u64 = 1000 * 60 * 1;
-
 const ROBOT_A_INIT_TIME_MS: u64 = 1000 * 5;
 const ROBOT_B_INIT_TIME_MS: u64 = 1000 * 5;
 const ROBOT_C_INIT_TIME_MS: u64 = 1000 * 5;
@@ -33,22 +45,35 @@ const ROBOT_B_CONSTANT_TIME_MS: u64 = 1000 * 86;
 const ROBOT_C_CONSTANT_TIME_MS: u64 = 1000 * 90;
 const ROBOT_D_CONSTANT_TIME_MS: u64 = 1000 * 93;
 
-const POSITION_INTERVAL_MS: u64 = 100;
+const ROBOT_A_MOTOR_ADDRESS: u8 = 3;
+const ROBOT_B_MOTOR_ADDRESS: u8 = 1;
+const ROBOT_C_MOTOR_ADDRESS: u8 = 2;
+const ROBOT_D_MOTOR_ADDRESS: u8 = 4;
 
-const BUFFERING_MIN_DELAY_MS: u64 = 1000 * 10;
-const BUFFERING_MAX_DELAY_MS: u64 = 1000 * 60;
+const POSITION_INTERVAL_MS: u64 = 100;
+/// Time a motor gets past twice its predicted move to report in position
+const MOVE_TIMEOUT_MARGIN_MS: u64 = 5000;
 
-const SCANNING_MIN_DELAY_MS: u64 = 1000 * 10;
-const SCANNING_MAX_DELAY_MS: u64 = 1000 * 60;
+// Keeps drawn speeds away from zero, see resolve_time_ms
+const MIN_RANDOM_SPEED: f64 = 0.01;
 
-const SYNCING_MIN_DELAY_MS: u64 = 1000 * 10;
-const SYNCING_MAX_DELAY_MS: u64 = 1000 * 60;
+/// Motor bus address of every robot, by name.
+pub fn motor_addresses() -> [(&'static str, u8); 4] {
+  [
+    ("A", ROBOT_A_MOTOR_ADDRESS),
+    ("B", ROBOT_B_MOTOR_ADDRESS),
+    ("C", ROBOT_C_MOTOR_ADDRESS),
+    ("D", ROBOT_D_MOTOR_ADDRESS),
+  ]
+}
 
 pub struct RobotManager {
   pub robot_a: Arc<Robot>,
   pub robot_b: Arc<Robot>,
   pub robot_c: Arc<Robot>,
   pub robot_d: Arc<Robot>,
+  rng: StdRng,
+  syncing_delay: Option<Sampler>,
 }
 
 impl RobotManager {
@@ -58,27 +83,35 @@ impl RobotManager {
       robot_a: Arc::new(Robot::new(
         0,
         "A",
+        ROBOT_A_MOTOR_ADDRESS,
         ROBOT_A_INIT_TIME_MS,
         ROBOT_A_CONSTANT_TIME_MS,
       )),
       robot_b: Arc::new(Robot::new(
         1,
         "B",
+        ROBOT_B_MOTOR_ADDRESS,
         ROBOT_B_INIT_TIME_MS,
         ROBOT_B_CONSTANT_TIME_MS,
       )),
       robot_c: Arc::new(Robot::new(
         2,
         "C",
+        ROBOT_C_MOTOR_ADDRESS,
         ROBOT_C_INIT_TIME_MS,
         ROBOT_C_CONSTANT_TIME_MS,
       )),
       robot_d: Arc::new(Robot::new(
         3,
         "D",
+        ROBOT_D_MOTOR_ADDRESS,
         ROBOT_D_INIT_TIME_MS,
         ROBOT_D_CONSTANT_TIME_MS,
       )),
+      rng: random::fork("RobotManager"),
+      syncing_delay: choreography::syncing_delay_sampler(
+        &show::get().choreography,
+      ),
     };
     // robot_manager.initialize_all().await;
     let ra = Arc::clone(&robot_manager.robot_a);
@@ -91,6 +124,15 @@ impl RobotManager {
     crate::log_exit!("---- RobotManager new", "");
     return robot_manager;
   }
+  pub fn robots(&self) -> Vec<Arc<Robot>> {
+    vec![
+      Arc::clone(&self.robot_a),
+      Arc::clone(&self.robot_b),
+      Arc::clone(&self.robot_c),
+      Arc::clone(&self.robot_d),
+    ]
+  }
+
   pub async fn initialize_all(&self) {
     crate::log_enter!("RobotManager initialize_all", "");
     self.robot_a.init().await;
@@ -111,12 +153,13 @@ impl RobotManager {
     crate::log_enter!("RobotManager stop_buffering", "");
   }
   pub async fn start_scanning(&mut self) {
-    let delay_a = get_scanning_delay();
-    let delay_b = get_scanning_delay();
-    let delay_c = get_scanning_delay();
-    let delay_d = get_scanning_delay();
+    let window = params::get().scanning_delay();
+    let delay_a = self.robot_a.sample_delay(|s| &mut s.scanning_delay, window);
+    let delay_b = self.robot_b.sample_delay(|s| &mut s.scanning_delay, window);
+    let delay_c = self.robot_c.sample_delay(|s| &mut s.scanning_delay, window);
+    let delay_d = self.robot_d.sample_delay(|s| &mut s.scanning_delay, window);
     let max_delay = get_max(vec![delay_a, delay_b, delay_c, delay_d]);
-    println!("Start scanning max delay in milliseconds: {}", max_delay);
+    tracing::info!(max_delay_ms = max_delay, "Start scanning");
     tokio::join!(
       // countdown(SCANNING_TIME_MS + max_delay),
       self.robot_a.start_scanning(delay_a),
@@ -126,35 +169,391 @@ impl RobotManager {
     );
   }
   pub async fn start_syncing(&mut self) {
-    let delay_a = get_syncing_delay();
-    let delay_b = get_syncing_delay();
-    let delay_c = get_syncing_delay();
-    let delay_d = get_syncing_delay();
-    let max_delay = get_max(vec![delay_a, delay_b, delay_c, delay_d]);
-    println!("Start syncing max delay in milliseconds: {}", max_delay);
+    let params = params::get();
+    let delay = choreography::sample_delay(
+      self.syncing_delay.as_mut(),
+      params.syncing_delay(),
+      &mut self.rng,
+    );
+    tracing::info!(delay_ms = delay, "Start syncing");
     tokio::join!(
-      // countdown(SYNCING_TIME_MS + max_delay),
-      self.robot_a.start_syncing(delay_a),
-      self.robot_b.start_syncing(delay_b),
-      self.robot_c.start_syncing(delay_c),
-      self.robot_d.start_syncing(delay_d),
+      self.robot_a.start_syncing(),
+      self.robot_b.start_syncing(),
+      self.robot_c.start_syncing(),
+      self.robot_d.start_syncing(),
+    );
+    utils::sleep(delay, "RobotManager start_syncing").await;
+    let targets = [
+      (Arc::clone(&self.robot_a), params.syncing_position),
+      (Arc::clone(&self.robot_b), params.syncing_position),
+      (Arc::clone(&self.robot_c), params.syncing_position),
+      (Arc::clone(&self.robot_d), params.syncing_position),
+    ];
+    let move_time_ms =
+      (params.syncing_move_time_ms as f64 / presence::speed_factor()) as u64;
+    if let Err(e) = self.coordinated_move(&targets, move_time_ms, 0).await {
+      tracing::warn!(error = %e, "Syncing move");
+    }
+    utils::sleep(
+      params.syncing_time_ms,
+      "RobotManager start_syncing SYNCING_TIME",
+    )
+    .await;
+  }
+
+  /// Stages every target with the ZDT sync flag and starts them all with a
+  /// single broadcast trigger, so the motors leave on the same tick.
+  pub async fn sync_move(
+    &self,
+    targets: &[(Arc<Robot>, f64)],
+    speed_rpm: f64,
+  ) -> anyhow::Result<()> {
+    crate::log_enter!("RobotManager sync_move", speed_rpm);
+    let mut moves = vec![];
+    for (robot, pos) in targets {
+      let Some(mut staged) = StagedMove::new(robot, *pos).await else {
+        continue;
+      };
+      staged.speed_rpm = speed_rpm;
+      staged.time = motors::time_for_trapezoid_ms(
+        staged.distance_deg(),
+        speed_rpm,
+        staged.acc_rpms,
+      );
+      moves.push(staged);
+    }
+    run_staged_moves(moves, 0).await?;
+    crate::log_exit!("RobotManager sync_move", speed_rpm);
+    Ok(())
+  }
+
+  /// Moves every robot to its target in `duration_ms`, each with the speed
+  /// resolved from its own distance, so they all arrive together. With a
+  /// `phase_offset_ms` each robot starts (and arrives) that much after the
+  /// previous one instead.
+  pub async fn coordinated_move(
+    &self,
+    targets: &[(Arc<Robot>, f64)],
+    duration_ms: u64,
+    phase_offset_ms: u64,
+  ) -> anyhow::Result<()> {
+    crate::log_enter!("RobotManager coordinated_move", duration_ms);
+    let mut moves = vec![];
+    for (robot, pos) in targets {
+      if let Some(staged) = StagedMove::new(robot, *pos).await {
+        moves.push(staged);
+      }
+    }
+    let mut duration_ms = duration_ms;
+    for staged in moves.iter_mut() {
+      staged.solve(duration_ms);
+    }
+    // The slowest robot sets the pace when the device limits are in the way
+    let slowest_ms = moves.iter().map(|m| m.time).max().unwrap_or(0);
+    if slowest_ms > duration_ms {
+      tracing::warn!(
+        duration_ms,
+        slowest_ms,
+        "Cannot cover the distances in time, stretching"
+      );
+      duration_ms = slowest_ms;
+      for staged in moves.iter_mut() {
+        staged.solve(duration_ms);
+      }
+    }
+    run_staged_moves(moves, phase_offset_ms).await?;
+    crate::log_exit!("RobotManager coordinated_move", duration_ms);
+    Ok(())
+  }
+
+  /// Moves every robot to `position` in `time_ms` and leaves them parked.
+  pub async fn park(&self, position: f64, time_ms: u64) -> anyhow::Result<()> {
+    let robots = self.robots();
+    for robot in &robots {
+      robot.transition(RobotState::Moving).await;
+    }
+    let targets: Vec<(Arc<Robot>, f64)> = robots
+      .iter()
+      .map(|robot| (Arc::clone(robot), position))
+      .collect();
+    let result = self.coordinated_move(&targets, time_ms, 0).await;
+    for robot in &robots {
+      if robot.state().await == RobotState::Moving {
+        robot.transition(RobotState::Parked).await;
+      }
+    }
+    result
+  }
+}
+
+/// A move resolved against the tracked robot position, ready to be sent to
+/// the motor bus.
+struct StagedMove {
+  robot: Arc<Robot>,
+  interrupt: watch::Receiver<Interrupt>,
+  current_position: f64,
+  mapped_position: f64,
+  speed_rpm: f64,
+  acc_rpms: f64,
+  time: u64,
+}
+
+impl StagedMove {
+  async fn new(robot: &Arc<Robot>, pos: f64) -> Option<Self> {
+    if robot.name == "B" {
+      tracing::warn!(robot = "B", "Not moving");
+      return None;
+    }
+    if robot.state().await == RobotState::Fault {
+      tracing::warn!(robot = robot.name, "Faulted, not moving");
+      return None;
+    }
+    Some(StagedMove {
+      robot: Arc::clone(robot),
+      interrupt: robot.claim_motion().0,
+      current_position: *robot.position.read().await,
+      mapped_position: map_position(pos),
+      speed_rpm: 0.0,
+      acc_rpms: motors::DEFAULT_ACC_RPMS,
+      time: 0,
+    })
+  }
+
+  fn solve(&mut self, duration_ms: u64) {
+    let distance_deg = self.distance_deg();
+    (self.speed_rpm, self.acc_rpms) =
+      motors::solve_profile(distance_deg, duration_ms);
+    self.time = motors::time_for_trapezoid_ms(
+      distance_deg,
+      self.speed_rpm,
+      self.acc_rpms,
+    );
+  }
+
+  fn spawn_interpolation(&self) -> tokio::task::JoinHandle<MoveOutcome> {
+    let robot = Arc::clone(&self.robot);
+    let mut interrupt = self.interrupt.clone();
+    let (current, mapped, time) =
+      (self.current_position, self.mapped_position, self.time);
+    let span = tracing::info_span!(
+      "move",
+      robot = robot.name,
+      mapped_position = mapped,
+      time_ms = time
+    );
+    tokio::spawn(
+      async move {
+        robot
+          .interpolate(current, mapped, time, &mut interrupt)
+          .await
+      }
+      .instrument(span),
+    )
+  }
+
+  fn distance_deg(&self) -> f64 {
+    (motors::position_to_deg(self.mapped_position)
+      - motors::position_to_deg(self.current_position))
+    .abs()
+  }
+}
+
+/// Without a phase offset every move is staged with the sync flag and
+/// started by one broadcast trigger, otherwise the moves are started one
+/// after the other, `phase_offset_ms` apart.
+async fn run_staged_moves(
+  moves: Vec<StagedMove>,
+  phase_offset_ms: u64,
+) -> anyhow::Result<()> {
+  if estop::latched().is_some() {
+    anyhow::bail!("Emergency stop latched");
+  }
+  let mut resting = vec![];
+  for staged in &moves {
+    if staged.robot.begin_move().await {
+      resting.push(Arc::clone(&staged.robot));
+    }
+  }
+  let result = stage_moves(moves, phase_offset_ms).await;
+  for robot in resting {
+    robot.end_move().await;
+  }
+  result
+}
+
+async fn stage_moves(
+  moves: Vec<StagedMove>,
+  phase_offset_ms: u64,
+) -> anyhow::Result<()> {
+  // Moves are timed from when they start, polling runs alongside staging
+  let (started, started_moves) = mpsc::unbounded_channel();
+  let (interpolations, ()) = tokio::join!(
+    start_moves(&moves, phase_offset_ms, started),
+    wait_in_position(&moves, started_moves),
+  );
+  futures::future::join_all(interpolations?).await;
+  Ok(())
+}
+
+/// Stages the moves and starts them, telling `started` the index of every
+/// move as it leaves.
+async fn start_moves(
+  moves: &[StagedMove],
+  phase_offset_ms: u64,
+  started: mpsc::UnboundedSender<(usize, Instant)>,
+) -> anyhow::Result<Vec<tokio::task::JoinHandle<MoveOutcome>>> {
+  let sync = phase_offset_ms == 0;
+  let mut interpolations = vec![];
+  for (index, staged) in moves.iter().enumerate() {
+    if !sync && index > 0 {
+      utils::sleep(phase_offset_ms, "RobotManager phase offset").await;
+    }
+    tracing::info!(
+      robot = staged.robot.name,
+      motor = staged.robot.motor_address,
+      mapped_position = staged.mapped_position,
+      speed_rpm = staged.speed_rpm,
+      "Staging move"
+    );
+    let result = motors::with_bus(|bus| {
+      bus.set_target(
+        staged.robot.motor_address,
+        motors::position_to_deg(staged.mapped_position),
+        staged.speed_rpm,
+        staged.acc_rpms,
+        sync,
+      )
+    });
+    journal::record_result(
+      &format!("robot {}", staged.robot.name),
+      "move",
+      json!({
+        "mapped_position": staged.mapped_position,
+        "speed_rpm": staged.speed_rpm,
+        "acc_rpms": staged.acc_rpms,
+        "sync": sync,
+      }),
+      &result,
     );
+    if let Err(e) = &result {
+      staged
+        .robot
+        .fault(&format!("Cannot stage move: {}", e))
+        .await;
+      if sync {
+        drop_staged(&moves[..=index]).await;
+      }
+    }
+    result?;
+    metrics::inc(&metrics::MOVES_COMMANDED, &[("robot", staged.robot.name)]);
+    if !sync {
+      // Not timed when the poller is gone, e.g. in dry run
+      let _ = started.send((index, Instant::now()));
+      interpolations.push(staged.spawn_interpolation());
+    }
   }
+  if sync {
+    tracing::info!("Triggering synchronized move");
+    let result = motors::with_bus(|bus| bus.sync_trigger());
+    journal::record_result("motors", "sync_trigger", json!({}), &result);
+    if result.is_err() {
+      drop_staged(moves).await;
+    }
+    result?;
+    let now = Instant::now();
+    for index in 0..moves.len() {
+      let _ = started.send((index, now));
+    }
+    interpolations = moves.iter().map(|m| m.spawn_interpolation()).collect();
+  }
+  Ok(interpolations)
 }
 
-#[derive(Debug, PartialEq)]
-pub enum RobotState {
-  Buffering,
-  Scanning,
-  Syncing,
+/// Stops the motors of moves staged with the sync flag, so the targets they
+/// hold do not leave with the next trigger.
+async fn drop_staged(moves: &[StagedMove]) {
+  for staged in moves {
+    if let Err(e) = staged.robot.cancel_move().await {
+      tracing::error!(
+        robot = staged.robot.name,
+        error = %e,
+        "Cannot drop staged move"
+      );
+    }
+  }
 }
 
-impl fmt::Display for RobotState {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    match self {
-      RobotState::Buffering => write!(f, "BUFFERING"),
-      RobotState::Scanning => write!(f, "SCANNING"),
-      RobotState::Syncing => write!(f, "SYNCING"),
+/// Polls the motors until they report in position, to time the moves
+/// against their prediction, then reads their supply voltage. Every move is
+/// polled from when it starts, until twice its predicted time. Nothing is
+/// timed in dry run, the mock bus reports no real motion.
+async fn wait_in_position(
+  moves: &[StagedMove],
+  mut started: mpsc::UnboundedReceiver<(usize, Instant)>,
+) {
+  if config::get(ConfigParam::DRYRUN) {
+    return;
+  }
+  let mut pending: Vec<(&StagedMove, Instant)> = vec![];
+  let mut staging = true;
+  let mut timed = vec![];
+  while staging || !pending.is_empty() {
+    tokio::select! {
+      next = started.recv(), if staging => match next {
+        Some((index, at)) => {
+          timed.push(&moves[index]);
+          pending.push((&moves[index], at));
+          continue;
+        }
+        None => staging = false,
+      },
+      _ = utils::sleep_silent(POSITION_INTERVAL_MS) => {}
+    }
+    let mut timed_out = vec![];
+    pending.retain(|(staged, started)| {
+      if *staged.interrupt.borrow() != Interrupt::None {
+        metrics::inc(
+          &metrics::MOVES_CANCELLED,
+          &[("robot", staged.robot.name)],
+        );
+        return false;
+      }
+      match motors::with_bus(|bus| bus.status(staged.robot.motor_address)) {
+        Ok(status) if status.in_position => {
+          metrics::move_completed(
+            staged.robot.name,
+            started.elapsed(),
+            staged.time,
+          );
+          return false;
+        }
+        Ok(_) => {}
+        Err(e) => {
+          tracing::warn!(robot = staged.robot.name, error = %e, "No status");
+          metrics::failure("serial");
+        }
+      }
+      let deadline =
+        Duration::from_millis(staged.time * 2 + MOVE_TIMEOUT_MARGIN_MS);
+      if started.elapsed() >= deadline {
+        timed_out.push(*staged);
+        return false;
+      }
+      true
+    });
+    for staged in timed_out {
+      metrics::inc(&metrics::MOVES_TIMED_OUT, &[("robot", staged.robot.name)]);
+      staged.robot.fault("Move did not complete in time").await;
+    }
+  }
+  for staged in timed {
+    let address = staged.robot.motor_address;
+    if let Ok(mv) = motors::with_bus(|bus| bus.bus_voltage_mv(address)) {
+      metrics::set(
+        &metrics::BUS_VOLTAGE,
+        &[("robot", staged.robot.name)],
+        mv as f64 / 1000.0,
+      );
     }
   }
 }
@@ -162,16 +561,22 @@ impl fmt::Display for RobotState {
 pub struct Robot {
   id: u8,
   name: &'static str,
+  motor_address: u8,
   init_time: u64,
-  state: RwLock<RobotState>,
+  state: RwLock<RobotStatus>,
   position: RwLock<f64>,
   speed_constant: u64,
+  rng: std::sync::Mutex<StdRng>,
+  samplers: std::sync::Mutex<RobotSamplers>,
+  /// Interrupts the running move, replaced by every new one
+  motion: std::sync::Mutex<Option<watch::Sender<Interrupt>>>,
 }
 
 impl Robot {
   fn new(
     id: u8,
     name: &'static str,
+    motor_address: u8,
     init_time: u64,
     speed_constant: u64,
   ) -> Self {
@@ -180,50 +585,127 @@ impl Robot {
     let robot = Robot {
       id,
       name,
+      motor_address,
       init_time,
-      state: RwLock::new(RobotState::Buffering),
+      state: RwLock::new(RobotStatus::new(RobotState::Idle)),
       // position: RwLock::new(0.0),
       position: RwLock::new(position),
       speed_constant,
+      rng: std::sync::Mutex::new(random::fork(name)),
+      samplers: std::sync::Mutex::new(RobotSamplers::new(
+        &show::get().choreography,
+        name,
+      )),
+      motion: std::sync::Mutex::new(None),
     };
     crate::log_exit!("Robot new", id);
     return robot;
   }
 
+  pub fn name(&self) -> &'static str {
+    self.name
+  }
+
+  /// Tracked mapped position (0.0-1.0), the one broadcast on `/robots`.
+  pub async fn position(&self) -> f64 {
+    *self.position.read().await
+  }
+
+  pub async fn state(&self) -> RobotState {
+    self.state.read().await.state
+  }
+
+  pub async fn status(&self) -> RobotStatus {
+    self.state.read().await.clone()
+  }
+
+  /// Moves the robot to `next`, false when its state machine refuses.
+  async fn transition(&self, next: RobotState) -> bool {
+    let mut status = self.state.write().await;
+    let from = status.state;
+    match status.transition(next) {
+      Ok(()) => {
+        if from != next {
+          tracing::info!(robot = self.name, %from, to = %next, "State");
+        }
+        true
+      }
+      Err(e) => {
+        tracing::warn!(robot = self.name, error = %e, "Transition refused");
+        false
+      }
+    }
+  }
+
+  /// Faults the robot, it stays out of the show until homed again.
+  pub async fn fault(&self, reason: &str) {
+    tracing::error!(robot = self.name, reason, "Robot fault");
+    self.state.write().await.fault(reason);
+  }
+
+  /// Marks a robot at rest as moving, true when it was at rest.
+  async fn begin_move(&self) -> bool {
+    self.state().await.is_resting() && self.transition(RobotState::Moving).await
+  }
+
+  /// Back to idle after `begin_move`, unless the move faulted.
+  async fn end_move(&self) {
+    if self.state().await == RobotState::Moving {
+      self.transition(RobotState::Idle).await;
+    }
+  }
+
   pub async fn init(&self) {
     crate::log_enter!("Robot init", &self.id);
+    if estop::latched().is_some() {
+      tracing::warn!(robot = self.name, "Emergency stop, not homing");
+      return;
+    }
+    if !self.transition(RobotState::Homing).await {
+      return;
+    }
     if config::get(ConfigParam::DRYRUN) {
       utils::print_dry_run("Invoked robot init script");
+      self.journal("init", json!({}));
       utils::sleep(self.init_time, "Robot init").await;
       self.print();
+      self.transition(RobotState::Idle).await;
       crate::log_exit!("Robot init", &self.id);
       return;
     }
     utils::invoke_script(&utils::ScriptName::RobotInit, &[&self.name]);
+    self.journal("init", json!({}));
+    self.transition(RobotState::Idle).await;
     // utils::sleep(self.init_time, "Robot init").await;
     crate::log_exit!("Robot init", &self.id);
   }
 
   pub async fn start_buffering(self: &Arc<Self>) {
     crate::log_enter!("Robot start_buffering", self.id);
-    {
-      let mut state = self.state.write().await;
-      *state = RobotState::Buffering;
+    if !self.transition(RobotState::Buffering).await {
+      return;
     }
     let start_time = std::time::Instant::now();
     for _ in 0..2 {
-      if *self.state.read().await != RobotState::Buffering {
+      if self.state().await != RobotState::Buffering {
         break;
       }
-      if start_time.elapsed().as_millis() as u64 >= BUFFERING_TIME_MS {
+      let params = params::get();
+      if start_time.elapsed().as_millis() as u64 >= params.buffering_time_ms {
         break;
       }
-      let delay = get_buffering_delay();
-      println!("Buffering delay for robot {}: {}", self.name, delay);
+      let delay =
+        self.sample_delay(|s| &mut s.buffering_delay, params.buffering_delay());
+      let random_position = self.sample(|s| &mut s.buffering_position);
+      let speed = self.sample(|s| &mut s.buffering_speed);
+      let random_speed =
+        (speed / presence::speed_factor()).max(MIN_RANDOM_SPEED);
+      tracing::info!(robot = self.name, delay_ms = delay, "Buffering delay");
       utils::sleep(delay, "Robot start_buffering").await;
-      let random_position = random_normal_value() * 5.0;
-      let random_speed = random_normal_value();
-      self.set_position(random_position, random_speed).await;
+      if let Err(e) = self.set_position(random_position, random_speed).await {
+        tracing::warn!(robot = self.name, error = %e, "Buffering move");
+        break;
+      }
     }
     crate::log_exit!("Robot start_buffering", self.id);
   }
@@ -255,28 +737,31 @@ impl Robot {
   pub async fn start_scanning(self: &Arc<Self>, delay: u64) {
     crate::log_enter!("Robot start_scanning", self.id);
     // self.stop().await;
-    {
-      let mut state = self.state.write().await;
-      *state = RobotState::Scanning;
+    if !self.transition(RobotState::Scanning).await {
+      return;
     }
     utils::sleep(delay, "Robot start_scanning").await;
-    let random_speed = random_normal_value();
-    self.set_position(SCANNING_POSITION, random_speed).await;
-    utils::sleep(SCANNING_TIME_MS, "Robot start_scanning SCANNING_TIME").await;
+    let params = params::get();
+    let position = self
+      .sample_defined(|s| &mut s.scanning_position)
+      .unwrap_or(params.scanning_position);
+    let speed = self.sample(|s| &mut s.scanning_speed);
+    let random_speed = (speed / presence::speed_factor()).max(MIN_RANDOM_SPEED);
+    if let Err(e) = self.set_position(position, random_speed).await {
+      tracing::warn!(robot = self.name, error = %e, "Scanning move");
+    }
+    utils::sleep(
+      params.scanning_time_ms,
+      "Robot start_scanning SCANNING_TIME",
+    )
+    .await;
     crate::log_exit!("Robot start_scanning", self.id);
   }
 
-  pub async fn start_syncing(self: &Arc<Self>, delay: u64) {
+  pub async fn start_syncing(self: &Arc<Self>) {
     crate::log_enter!("Robot start_syncing", self.id);
-    // self.stop().await;
-    {
-      let mut state = self.state.write().await;
-      *state = RobotState::Syncing;
-    }
-    utils::sleep(delay, "Robot start_syncing").await;
-    let random_speed = random_normal_value();
-    self.set_position(SYNCING_POSITION, random_speed).await;
-    utils::sleep(SYNCING_TIME_MS, "Robot start_syncing SYNCYING_TIME").await;
+    // The move itself is staged by RobotManager::sync_move
+    self.transition(RobotState::Syncing).await;
     crate::log_exit!("Robot start_syncing", self.id);
   }
 
@@ -288,15 +773,200 @@ impl Robot {
   //   return current_position;
   // }
 
-  pub async fn set_position(self: &Arc<Self>, pos: f64, speed: f64) {
+  /// Moves the robot, pre-empting its running move.
+  pub async fn set_position(
+    self: &Arc<Self>,
+    pos: f64,
+    speed: f64,
+  ) -> anyhow::Result<MoveOutcome> {
     if self.name == "B" {
-      println!("ROBOT B - NOT MOVING");
-      return ();
+      tracing::warn!(robot = "B", "Not moving");
+      return Ok(MoveOutcome::Completed);
     }
     crate::log_enter!("Robot set_position", pos);
+    if estop::latched().is_some() {
+      anyhow::bail!("Emergency stop latched");
+    }
+    if self.state().await == RobotState::Fault {
+      anyhow::bail!("Robot {} is faulted", self.name);
+    }
+    let (mut interrupt, preempted) = self.claim_motion();
+    // A pre-empted move outside the show hands its robot over to this one
+    let owns_move = self.begin_move().await
+      || (preempted && self.state().await == RobotState::Moving);
     // let current_position = *self.position.read().await;
+    let tracked_position = *self.position.read().await;
     let current_position = self.get_real_position().await;
-    println!("Current position is {}", current_position);
+    if !config::get(ConfigParam::DRYRUN) {
+      metrics::set(
+        &metrics::POSITION_ERROR,
+        &[("robot", self.name)],
+        (current_position - tracked_position).abs(),
+      );
+    }
+    let outcome = self
+      .move_from(current_position, pos, speed, &mut interrupt)
+      .await;
+    if owns_move && outcome != MoveOutcome::Preempted {
+      self.end_move().await;
+    }
+    crate::log_exit!("Robot set_position", pos);
+    Ok(outcome)
+  }
+
+  /// Runs `set_position` as its own task.
+  pub fn start_move(self: &Arc<Self>, pos: f64, speed: f64) -> MoveHandle {
+    let robot = Arc::clone(self);
+    let task =
+      tokio::spawn(async move { robot.set_position(pos, speed).await });
+    MoveHandle::new(Arc::clone(self), task)
+  }
+
+  /// Stops the robot where it is: ends its running move and sends the
+  /// motor the stop command.
+  pub async fn cancel_move(&self) -> anyhow::Result<()> {
+    if let Some(motion) = self.motion.lock().unwrap().as_ref() {
+      motion.send_replace(Interrupt::Cancel);
+    }
+    let result = motors::with_bus(|bus| bus.stop(self.motor_address));
+    journal::record_result(
+      &format!("robot {}", self.name),
+      "stop",
+      json!({}),
+      &result,
+    );
+    result
+  }
+
+  /// Starts a new move, pre-empting the running one. True when there was
+  /// one.
+  fn claim_motion(&self) -> (watch::Receiver<Interrupt>, bool) {
+    let (sender, receiver) = watch::channel(Interrupt::None);
+    let preempted = match self.motion.lock().unwrap().replace(sender) {
+      Some(previous) if !previous.is_closed() => {
+        previous.send_replace(Interrupt::Preempt);
+        true
+      }
+      _ => false,
+    };
+    (receiver, preempted)
+  }
+
+  /// How long `run_trajectory` is expected to take from the tracked
+  /// position: the moves to every waypoint and the dwells on them.
+  pub async fn trajectory_time_ms(&self, trajectory: &Trajectory) -> u64 {
+    match trajectory {
+      Trajectory::Waypoints(waypoints) => {
+        let mut current_position = *self.position.read().await;
+        let mut time_ms = 0;
+        for waypoint in waypoints {
+          let mapped_position = map_position(waypoint.position);
+          time_ms += resolve_time_ms(
+            &current_position,
+            &mapped_position,
+            &waypoint.speed,
+            &(self.speed_constant as f64),
+          ) + waypoint.dwell_ms;
+          current_position = mapped_position;
+        }
+        time_ms
+      }
+      Trajectory::Spline { .. } => trajectory.duration_ms(),
+    }
+  }
+
+  pub async fn run_trajectory(self: &Arc<Self>, trajectory: &Trajectory) {
+    if self.name == "B" {
+      tracing::warn!(robot = "B", "Not moving");
+      return;
+    }
+    crate::log_enter!("Robot run_trajectory", self.id);
+    if estop::latched().is_some() {
+      tracing::warn!(robot = self.name, "Emergency stop, not moving");
+      return;
+    }
+    if self.state().await == RobotState::Fault {
+      tracing::warn!(robot = self.name, "Faulted, not moving");
+      return;
+    }
+    let resting = self.begin_move().await;
+    match trajectory {
+      Trajectory::Waypoints(waypoints) => {
+        for waypoint in waypoints {
+          match self.set_position(waypoint.position, waypoint.speed).await {
+            Ok(MoveOutcome::Completed) => {}
+            Ok(outcome) => {
+              tracing::info!(robot = self.name, ?outcome, "Trajectory ended");
+              break;
+            }
+            Err(e) => {
+              tracing::warn!(robot = self.name, error = %e, "Trajectory ended");
+              break;
+            }
+          }
+          if waypoint.dwell_ms > 0 {
+            utils::sleep(waypoint.dwell_ms, "Robot run_trajectory dwell").await;
+          }
+        }
+      }
+      Trajectory::Spline {
+        keyframes,
+        interval_ms,
+      } => {
+        // Read the real position once, then follow the streamed targets
+        let (mut interrupt, _) = self.claim_motion();
+        let mut current_position = self.get_real_position().await;
+        let started = Instant::now();
+        let interval_ms = (*interval_ms).max(POSITION_INTERVAL_MS);
+        for (time_ms, pos) in trajectory::sample_spline(keyframes, interval_ms)
+        {
+          // Against the trajectory clock, so the time spent in the position
+          // script is taken off the next segment instead of adding up
+          let elapsed_ms = started.elapsed().as_millis() as u64;
+          let segment_ms =
+            time_ms.saturating_sub(elapsed_ms).max(POSITION_INTERVAL_MS);
+          match resolve_speed(
+            &current_position,
+            &map_position(pos),
+            &segment_ms,
+            &(self.speed_constant as f64),
+          ) {
+            Some(speed) => {
+              let outcome = self
+                .move_from(current_position, pos, speed, &mut interrupt)
+                .await;
+              if outcome != MoveOutcome::Completed {
+                tracing::info!(robot = self.name, ?outcome, "Trajectory ended");
+                break;
+              }
+            }
+            None => utils::sleep_silent(segment_ms).await,
+          }
+          current_position = *self.position.read().await;
+        }
+      }
+    }
+    if resting {
+      self.end_move().await;
+    }
+    crate::log_exit!("Robot run_trajectory", self.id);
+  }
+
+  #[tracing::instrument(
+    name = "move",
+    skip(self, interrupt),
+    fields(robot = self.name)
+  )]
+  async fn move_from(
+    self: &Arc<Self>,
+    current_position: f64,
+    pos: f64,
+    speed: f64,
+    interrupt: &mut watch::Receiver<Interrupt>,
+  ) -> MoveOutcome {
+    tracing::debug!(current_position, "Current position");
+    metrics::inc(&metrics::MOVES_COMMANDED, &[("robot", self.name)]);
+    let started = Instant::now();
     if config::get(ConfigParam::DRYRUN) {
       utils::print_dry_run(
         format!("Invoked robot set position script {} {}", pos, speed).as_str(),
@@ -307,40 +977,74 @@ impl Robot {
         &[self.name, &pos.to_string(), &speed.to_string()],
       );
     }
+    self.journal("move", json!({ "position": pos, "speed": speed }));
     let mapped_position = map_position(pos);
-    println!("Mapped position {}", mapped_position);
-    println!("Current mapped position {}", current_position);
+    tracing::debug!(mapped_position, current_position, "Mapped position");
     let time = resolve_time_ms(
       &current_position,
       &mapped_position,
       &speed,
       &(self.speed_constant as f64),
     );
-    println!("Resolved time {}", time);
+    tracing::debug!(time_ms = time, "Resolved time");
+    let outcome = self
+      .interpolate(current_position, mapped_position, time, interrupt)
+      .await;
+    if outcome == MoveOutcome::Completed {
+      metrics::move_completed(self.name, started.elapsed(), time);
+    } else {
+      metrics::inc(&metrics::MOVES_CANCELLED, &[("robot", self.name)]);
+    }
+    outcome
+  }
+
+  /// Follows the motor with the tracked position, in `POSITION_INTERVAL_MS`
+  /// steps, until it is expected to reach `mapped_position` or the move is
+  /// interrupted where it got to.
+  async fn interpolate(
+    &self,
+    current_position: f64,
+    mapped_position: f64,
+    time: u64,
+    interrupt: &mut watch::Receiver<Interrupt>,
+  ) -> MoveOutcome {
     let delta = mapped_position - current_position;
-    println!("Resolved delta {}", delta);
     let steps = ((time / POSITION_INTERVAL_MS) as f64).ceil() as usize;
-    println!("Resolved steps {}", steps);
     let step_size = delta / steps as f64;
-    println!("Resolved steps_size {}", step_size);
+    tracing::debug!(delta, steps, step_size, "Interpolating");
     {
       let mut p = self.position.write().await;
       *p = current_position;
     }
     for _ in 0..steps {
+      if *interrupt.borrow() != Interrupt::None {
+        break;
+      }
       {
         let mut p = self.position.write().await;
         *p += step_size;
       }
-      utils::sleep_silent(POSITION_INTERVAL_MS).await;
+      tokio::select! {
+        _ = utils::sleep_silent(POSITION_INTERVAL_MS) => {}
+        _ = interrupt.changed() => {}
+      }
+    }
+    let outcome = interrupt.borrow().outcome();
+    let final_position = if outcome == MoveOutcome::Completed {
+      mapped_position
+    } else {
+      tracing::info!(robot = self.name, ?outcome, "Move interrupted");
+      *self.position.read().await
+    };
+    store_position(self.name, final_position).expect("Cannot store file");
+    tracing::debug!("Interpolation stopped");
+    {
+      let mut p = self.position.write().await;
+      *p = final_position;
     }
-    store_position(&self.name, mapped_position).expect("Cannot store file");
-    println!("Interpolation stopped");
-    let mut p = self.position.write().await;
-    *p = mapped_position;
     let after_position = *self.position.read().await;
-    println!("Current position is {}", after_position);
-    crate::log_exit!("Robot set_position", mapped_position);
+    tracing::debug!(current_position = after_position, "Current position");
+    outcome
   }
 
   pub async fn get_real_position(&self) -> f64 {
@@ -361,13 +1065,13 @@ impl Robot {
         .filter(|line| !line.trim().is_empty())
         .last()
         .expect("No lines in script output");
-      println!("Get real position response:\n{}", last_line);
+      tracing::debug!(response = last_line, "Get real position");
       let number_value: f64 = last_line.parse().expect("Cannot parse real pos");
       let inverted = number_value * -1.0;
       inverted
     };
     let mapped_position = map_position(pos);
-    println!("Current position mapped is {}", mapped_position);
+    tracing::debug!(mapped_position, "Current position mapped");
     crate::log_exit!("Robot get_real_position", mapped_position);
     return mapped_position;
   }
@@ -394,8 +1098,53 @@ impl Robot {
   //   // return unwrapped;
   // }
 
+  /// Draws a choreography parameter from the robot's own random stream.
+  fn sample(&self, select: fn(&mut RobotSamplers) -> &mut Sampler) -> f64 {
+    let mut rng = self.rng.lock().unwrap();
+    let mut samplers = self.samplers.lock().unwrap();
+    select(&mut samplers).sample(&mut rng)
+  }
+
+  /// Draws a choreography parameter the show may leave undefined.
+  fn sample_defined(
+    &self,
+    select: fn(&mut RobotSamplers) -> &mut Option<Sampler>,
+  ) -> Option<f64> {
+    let mut rng = self.rng.lock().unwrap();
+    let mut samplers = self.samplers.lock().unwrap();
+    select(&mut samplers)
+      .as_mut()
+      .map(|sampler| sampler.sample(&mut rng))
+  }
+
+  /// Draws a delay from the show, or from the live `window` without one.
+  fn sample_delay(
+    &self,
+    select: fn(&mut RobotSamplers) -> &mut Option<Sampler>,
+    window: (u64, u64),
+  ) -> u64 {
+    let mut rng = self.rng.lock().unwrap();
+    let mut samplers = self.samplers.lock().unwrap();
+    choreography::sample_delay(select(&mut samplers).as_mut(), window, &mut rng)
+  }
+
+  fn journal(&self, command: &str, params: Value) {
+    journal::record(
+      &format!("robot {}", self.name),
+      command,
+      params,
+      Outcome::Ok,
+      None,
+    );
+  }
+
   fn print(&self) {
-    println!("ROBOT {} {} {}", self.id, self.name, self.init_time);
+    tracing::info!(
+      id = self.id,
+      robot = self.name,
+      init_time = self.init_time,
+      "ROBOT"
+    );
   }
 }
 
@@ -405,30 +1154,29 @@ fn resolve_time_ms(
   speed: &f64,
   k: &f64,
 ) -> u64 {
-  println!(
-    "Invoked resolve_time_ms with current: {}, position: {}, speed: {}, k: {}",
-    current_position, position, speed, k
-  );
+  tracing::debug!(current_position, position, speed, k, "Resolving time");
   if *speed <= 0.0 {
     panic!("Speed must be greater than zero");
   }
   let distance = (position - current_position).abs();
-  println!("Distance: {}", distance);
   let time = distance * speed * k;
-  println!("Time: {}", time);
+  tracing::debug!(distance, time, "Resolved time");
   time.ceil() as u64 // Round up to ensure enough time to reach
 }
 
-fn random_normal_value() -> f64 {
-  let mut rng = rand::thread_rng();
-  let random_value: f64 = rng.gen_range(0.1..0.9);
-  return random_value;
-}
-
-fn random_integer_value(from: u64, to: u64) -> u64 {
-  let mut rng = rand::thread_rng();
-  let random_value: u64 = rng.gen_range(from..to);
-  return random_value;
+/// Inverse of `resolve_time_ms`: the speed that covers the distance in
+/// `time_ms`. `None` when there is no distance to cover.
+fn resolve_speed(
+  current_position: &f64,
+  position: &f64,
+  time_ms: &u64,
+  k: &f64,
+) -> Option<f64> {
+  let distance = (position - current_position).abs();
+  if distance < f64::EPSILON || *time_ms == 0 {
+    return None;
+  }
+  Some(*time_ms as f64 / (distance * k))
 }
 
 fn get_max(values: Vec<u64>) -> u64 {
@@ -456,7 +1204,7 @@ async fn start_service(
   robot_c: Arc<Robot>,
   robot_d: Arc<Robot>,
 ) {
-  println!("Starting Robot Service...");
+  tracing::info!("Starting Robot Service...");
   let socket = UdpSocket::bind("0.0.0.0:0").expect("could not bind socket");
   socket
     .set_broadcast(true)
@@ -469,7 +1217,18 @@ async fn start_service(
       *robot_c.position.read().await,
       *robot_d.position.read().await,
     ];
-    let state = robot_a.state.read().await;
+    let statuses = [
+      ("A", robot_a.status().await),
+      ("B", robot_b.status().await),
+      ("C", robot_c.status().await),
+      ("D", robot_d.status().await),
+    ];
+    // A fault anywhere shows first, the details are on /robots/state
+    let state = statuses
+      .iter()
+      .map(|(_, status)| status.state)
+      .find(|state| *state == RobotState::Fault)
+      .unwrap_or(statuses[0].1.state);
 
     let mut msg_string = positions
       .iter()
@@ -487,34 +1246,24 @@ async fn start_service(
     let buf = encoder::encode(&packet).unwrap();
     socket.send_to(&buf, SERVICE_ADDRESS).unwrap();
 
+    for (name, status) in statuses {
+      let msg = OscMessage {
+        addr: "/robots/state".into(),
+        args: vec![
+          OscType::String(String::from(name)),
+          OscType::String(status.state.to_string()),
+          OscType::Float(status.seconds() as f32),
+          OscType::String(status.fault.unwrap_or_default()),
+        ],
+      };
+      let buf = encoder::encode(&OscPacket::Message(msg)).unwrap();
+      socket.send_to(&buf, SERVICE_ADDRESS).unwrap();
+    }
+
     utils::sleep_silent(POSITION_INTERVAL_MS).await;
   }
 }
 
-fn get_buffering_delay() -> u64 {
-  let delay = random_integer_value(
-    BUFFERING_MIN_DELAY_MS / 1000,
-    BUFFERING_MAX_DELAY_MS / 1000,
-  ) * 1000;
-  return delay;
-}
-
-fn get_scanning_delay() -> u64 {
-  let delay = random_integer_value(
-    SCANNING_MIN_DELAY_MS / 1000,
-    SCANNING_MAX_DELAY_MS / 1000,
-  ) * 1000;
-  return delay;
-}
-
-fn get_syncing_delay() -> u64 {
-  let delay = random_integer_value(
-    SYNCING_MIN_DELAY_MS / 1000,
-    SYNCING_MAX_DELAY_MS / 1000,
-  ) * 1000;
-  return delay;
-}
-
 // pub fn create(id: &str) -> Arc<Robot> {
 //   match id {
 //     "1" => Arc::new(Robot::new(
@@ -573,7 +1322,7 @@ pub fn store_position(robot_id: &str, position: f64) -> io::Result<()> {
   let file_path = format!("/tmp/robot-position-{}.txt", robot_id);
   let mut file = File::create(&file_path)?;
   write!(file, "{}", position)?;
-  println!("Stored position {} in file {}", position, file_path);
+  tracing::debug!(position, file = file_path, "Stored position");
   Ok(())
 }
 
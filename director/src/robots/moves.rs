@@ -0,0 +1,63 @@
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use super::Robot;
+
+/// How a move ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MoveOutcome {
+  Completed,
+  /// Stopped before reaching its target
+  Cancelled,
+  /// Replaced by a newer move of the same robot
+  Preempted,
+}
+
+/// Sent to the running move of a robot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+  None,
+  Cancel,
+  Preempt,
+}
+
+impl Interrupt {
+  pub fn outcome(self) -> MoveOutcome {
+    match self {
+      Interrupt::None => MoveOutcome::Completed,
+      Interrupt::Cancel => MoveOutcome::Cancelled,
+      Interrupt::Preempt => MoveOutcome::Preempted,
+    }
+  }
+}
+
+/// A move running as its own task, see `Robot::start_move`.
+pub struct MoveHandle {
+  robot: Arc<Robot>,
+  task: JoinHandle<anyhow::Result<MoveOutcome>>,
+}
+
+impl MoveHandle {
+  pub fn new(
+    robot: Arc<Robot>,
+    task: JoinHandle<anyhow::Result<MoveOutcome>>,
+  ) -> Self {
+    MoveHandle { robot, task }
+  }
+
+  /// Stops the robot where it is, the move ends as cancelled.
+  pub async fn cancel(&self) -> anyhow::Result<()> {
+    self.robot.cancel_move().await
+  }
+
+  /// Sends the robot to a new target from where it is, without stopping.
+  /// This move ends as pre-empted.
+  pub fn retarget(self, pos: f64, speed: f64) -> MoveHandle {
+    self.robot.start_move(pos, speed)
+  }
+
+  /// Waits for the move to end.
+  pub async fn wait(&mut self) -> anyhow::Result<MoveOutcome> {
+    (&mut self.task).await?
+  }
+}
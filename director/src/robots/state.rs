@@ -0,0 +1,108 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What a robot is doing. The phases of the show are entered from rest or
+/// from another phase, a fault holds until the robot is homed again.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RobotState {
+  /// At rest, homed or after a move outside the show
+  Idle,
+  /// Running its init script
+  Homing,
+  Buffering,
+  Scanning,
+  Syncing,
+  /// A move outside the show phases, e.g. from the command line or parking
+  Moving,
+  /// At rest in the park position while the installation is closed
+  Parked,
+  Fault,
+}
+
+impl RobotState {
+  pub fn can_become(self, next: RobotState) -> bool {
+    match (self, next) {
+      (current, next) if current == next => true,
+      (_, RobotState::Fault) => true,
+      (RobotState::Fault, next) => next == RobotState::Homing,
+      (RobotState::Homing, next) => next == RobotState::Idle,
+      (_, RobotState::Homing) => true,
+      (RobotState::Moving, next) => {
+        matches!(next, RobotState::Idle | RobotState::Parked)
+      }
+      (_, RobotState::Moving) => true,
+      (_, RobotState::Idle | RobotState::Parked) => false,
+      (
+        _,
+        RobotState::Buffering | RobotState::Scanning | RobotState::Syncing,
+      ) => true,
+    }
+  }
+
+  /// Idle or parked, nothing the show is waiting on.
+  pub fn is_resting(self) -> bool {
+    matches!(self, RobotState::Idle | RobotState::Parked)
+  }
+}
+
+impl fmt::Display for RobotState {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RobotState::Idle => write!(f, "IDLE"),
+      RobotState::Homing => write!(f, "HOMING"),
+      RobotState::Buffering => write!(f, "BUFFERING"),
+      RobotState::Scanning => write!(f, "SCANNING"),
+      RobotState::Syncing => write!(f, "SYNCING"),
+      RobotState::Moving => write!(f, "MOVING"),
+      RobotState::Parked => write!(f, "PARKED"),
+      RobotState::Fault => write!(f, "FAULT"),
+    }
+  }
+}
+
+/// State of a robot with when it was entered.
+#[derive(Debug, Clone, Serialize)]
+pub struct RobotStatus {
+  pub state: RobotState,
+  pub since: DateTime<Local>,
+  /// Why the robot faulted, only in `Fault`
+  pub fault: Option<String>,
+}
+
+impl RobotStatus {
+  pub fn new(state: RobotState) -> Self {
+    RobotStatus {
+      state,
+      since: Local::now(),
+      fault: None,
+    }
+  }
+
+  /// Moves to `next` when allowed, keeping the entry time when the state
+  /// does not change.
+  pub fn transition(&mut self, next: RobotState) -> anyhow::Result<()> {
+    if !self.state.can_become(next) {
+      anyhow::bail!("Cannot go from {} to {}", self.state, next);
+    }
+    if self.state != next {
+      *self = RobotStatus::new(next);
+    }
+    Ok(())
+  }
+
+  /// Faults with `reason`, the first reason is kept while faulted.
+  pub fn fault(&mut self, reason: &str) {
+    if self.state != RobotState::Fault {
+      *self = RobotStatus {
+        fault: Some(String::from(reason)),
+        ..RobotStatus::new(RobotState::Fault)
+      };
+    }
+  }
+
+  pub fn seconds(&self) -> f64 {
+    (Local::now() - self.since).num_milliseconds() as f64 / 1000.0
+  }
+}
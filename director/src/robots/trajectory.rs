@@ -0,0 +1,154 @@
+use std::f64::consts::PI;
+
+/// A single stop of a multi-waypoint trajectory. `position` and `speed` use
+/// the same units as `Robot::set_position`, `dwell_ms` is the pause after the
+/// robot reached the waypoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waypoint {
+  pub position: f64,
+  pub speed: f64,
+  pub dwell_ms: u64,
+}
+
+/// A point of a spline trajectory: where the robot must be `time_ms`
+/// milliseconds after the trajectory started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe {
+  pub time_ms: u64,
+  pub position: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trajectory {
+  /// Move to each waypoint in order, waiting `dwell_ms` on each one.
+  Waypoints(Vec<Waypoint>),
+  /// Position over time through the keyframes (Catmull-Rom), streamed to the
+  /// robot as intermediate targets every `interval_ms`.
+  Spline {
+    keyframes: Vec<Keyframe>,
+    interval_ms: u64,
+  },
+}
+
+impl Trajectory {
+  /// Slow oscillation around `center`, `cycles` times, each lasting
+  /// `period_ms`. Starts and ends on `center`.
+  pub fn breathing(
+    center: f64,
+    amplitude: f64,
+    period_ms: u64,
+    cycles: u32,
+    interval_ms: u64,
+  ) -> Self {
+    let quarter = period_ms / 4;
+    let mut keyframes = vec![];
+    for step in 0..=(cycles as u64 * 4) {
+      let phase = (step % 4) as f64 * PI / 2.0;
+      keyframes.push(Keyframe {
+        time_ms: step * quarter,
+        position: center + amplitude * phase.sin(),
+      });
+    }
+    Trajectory::Spline {
+      keyframes,
+      interval_ms,
+    }
+  }
+
+  /// Parses waypoints written as `<pos>:<speed>[:<dwell_ms>]`.
+  pub fn parse_waypoints(args: &[String]) -> anyhow::Result<Self> {
+    let mut waypoints = vec![];
+    for arg in args {
+      let parts: Vec<&str> = arg.split(':').collect();
+      if parts.len() < 2 || parts.len() > 3 {
+        anyhow::bail!(
+          "Invalid waypoint {}, expected <pos>:<speed>[:<dwell_ms>]",
+          arg
+        );
+      }
+      let speed: f64 = parts[1].parse()?;
+      if speed <= 0.0 {
+        anyhow::bail!(
+          "Invalid waypoint {}, speed must be greater than zero",
+          arg
+        );
+      }
+      waypoints.push(Waypoint {
+        position: parts[0].parse()?,
+        speed,
+        dwell_ms: match parts.get(2) {
+          Some(dwell) => dwell.parse()?,
+          None => 0,
+        },
+      });
+    }
+    if waypoints.is_empty() {
+      anyhow::bail!("A trajectory needs at least one waypoint");
+    }
+    Ok(Trajectory::Waypoints(waypoints))
+  }
+
+  /// Known duration of the trajectory, not counting the moves between
+  /// waypoints which depend on where the robot currently is.
+  pub fn duration_ms(&self) -> u64 {
+    match self {
+      Trajectory::Waypoints(waypoints) => {
+        waypoints.iter().map(|w| w.dwell_ms).sum()
+      }
+      Trajectory::Spline { keyframes, .. } => {
+        keyframes.last().map(|k| k.time_ms).unwrap_or(0)
+      }
+    }
+  }
+}
+
+/// Samples the spline every `interval_ms`, always including the last
+/// keyframe. Returns `(time_ms, position)` pairs.
+pub fn sample_spline(
+  keyframes: &[Keyframe],
+  interval_ms: u64,
+) -> Vec<(u64, f64)> {
+  let Some(last) = keyframes.last() else {
+    return vec![];
+  };
+  let interval_ms = interval_ms.max(1);
+  let mut samples = vec![];
+  let mut time_ms = interval_ms;
+  while time_ms < last.time_ms {
+    samples.push((time_ms, spline_position(keyframes, time_ms)));
+    time_ms += interval_ms;
+  }
+  samples.push((last.time_ms, last.position));
+  samples
+}
+
+/// Catmull-Rom interpolation of the keyframes at `time_ms`.
+fn spline_position(keyframes: &[Keyframe], time_ms: u64) -> f64 {
+  let index = keyframes
+    .iter()
+    .rposition(|k| k.time_ms <= time_ms)
+    .unwrap_or(0);
+  if index + 1 >= keyframes.len() {
+    return keyframes[keyframes.len() - 1].position;
+  }
+  let p1 = &keyframes[index];
+  let p2 = &keyframes[index + 1];
+  let p0 = if index == 0 {
+    p1
+  } else {
+    &keyframes[index - 1]
+  };
+  let p3 = keyframes.get(index + 2).unwrap_or(p2);
+  let span = (p2.time_ms - p1.time_ms).max(1) as f64;
+  let t = (time_ms - p1.time_ms) as f64 / span;
+  let t2 = t * t;
+  let t3 = t2 * t;
+  0.5
+    * ((2.0 * p1.position)
+      + (-p0.position + p2.position) * t
+      + (2.0 * p0.position - 5.0 * p1.position + 4.0 * p2.position
+        - p3.position)
+        * t2
+      + (-p0.position + 3.0 * p1.position - 3.0 * p2.position + p3.position)
+        * t3)
+}
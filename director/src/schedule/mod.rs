@@ -0,0 +1,228 @@
+use chrono::{
+  DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta,
+  Weekday,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::show;
+use crate::utils;
+
+/// Days ahead looked at for the next opening
+const LOOKAHEAD_DAYS: usize = 14;
+
+/// Where the installation is in its day.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+  Closed,
+  /// Robots are homed before opening
+  WarmUp,
+  Open,
+}
+
+/// Opening and closing time of a day, e.g. `{ "open": "10:30", "close":
+/// "20:00" }`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Hours {
+  #[serde(deserialize_with = "deserialize_time")]
+  pub open: NaiveTime,
+  #[serde(deserialize_with = "deserialize_time")]
+  pub close: NaiveTime,
+}
+
+/// A day with its own hours, open even on a closed day.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Event {
+  pub date: NaiveDate,
+  #[serde(flatten)]
+  pub hours: Hours,
+}
+
+/// `schedule` section of the show file, evaluated in local time. Without it
+/// the installation runs as soon as it is started.
+///
+/// ```json
+/// {
+///   "enabled": true,
+///   "hours": { "tue": { "open": "10:30", "close": "20:00" } },
+///   "closed": ["2025-12-25"],
+///   "events": [{ "date": "2025-06-12", "open": "18:00", "close": "23:30" }]
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Schedule {
+  pub enabled: bool,
+  /// Opening hours by weekday (`mon` to `sun`), days without are closed
+  pub hours: HashMap<Weekday, Hours>,
+  /// Dates closed whatever their weekday
+  pub closed: Vec<NaiveDate>,
+  pub events: Vec<Event>,
+  /// Minutes before opening the robots are homed
+  pub warm_up_min: u32,
+  /// Position the robots rest in while closed
+  pub park_position: f64,
+  pub park_time_ms: u64,
+}
+
+impl Default for Schedule {
+  fn default() -> Self {
+    Schedule {
+      enabled: false,
+      hours: HashMap::new(),
+      closed: vec![],
+      events: vec![],
+      warm_up_min: 15,
+      park_position: 0.0,
+      park_time_ms: 30000,
+    }
+  }
+}
+
+impl Schedule {
+  pub fn validate(&self) -> anyhow::Result<()> {
+    let days = self
+      .hours
+      .iter()
+      .map(|(day, hours)| (day.to_string(), hours));
+    let events = self
+      .events
+      .iter()
+      .map(|event| (event.date.to_string(), &event.hours));
+    for (day, hours) in days.chain(events) {
+      if hours.open >= hours.close {
+        anyhow::bail!("{} must open before it closes", day);
+      }
+    }
+    Ok(())
+  }
+
+  /// Hours of `day`, `None` when closed all day.
+  pub fn hours_on(&self, day: NaiveDate) -> Option<Hours> {
+    if let Some(event) = self.events.iter().find(|event| event.date == day) {
+      return Some(event.hours);
+    }
+    if self.closed.contains(&day) {
+      return None;
+    }
+    self.hours.get(&day.weekday()).copied()
+  }
+
+  pub fn period_at(&self, now: NaiveDateTime) -> Period {
+    let Some(hours) = self.hours_on(now.date()) else {
+      return Period::Closed;
+    };
+    let open = now.date().and_time(hours.open);
+    let close = now.date().and_time(hours.close);
+    if now >= open && now < close {
+      Period::Open
+    } else if now < open && now >= open - self.warm_up() {
+      Period::WarmUp
+    } else {
+      Period::Closed
+    }
+  }
+
+  /// When the period changes next, and to what.
+  pub fn next_change(
+    &self,
+    now: NaiveDateTime,
+  ) -> Option<(NaiveDateTime, Period)> {
+    let current = self.period_at(now);
+    now
+      .date()
+      .iter_days()
+      .take(LOOKAHEAD_DAYS)
+      .filter_map(|day| Some((day, self.hours_on(day)?)))
+      .flat_map(|(day, hours)| {
+        let open = day.and_time(hours.open);
+        [open - self.warm_up(), open, day.and_time(hours.close)]
+      })
+      .filter(|at| *at > now)
+      .map(|at| (at, self.period_at(at)))
+      .find(|(_, period)| *period != current)
+  }
+
+  fn warm_up(&self) -> TimeDelta {
+    TimeDelta::minutes(self.warm_up_min as i64)
+  }
+}
+
+/// Manual override of the schedule, kept in a file so it survives a restart
+/// and can be set while the installation runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Override {
+  pub period: Period,
+  /// Back to the schedule after this time
+  pub until: Option<DateTime<Local>>,
+}
+
+fn override_path() -> PathBuf {
+  utils::get_home_dir().join("triennale-25/schedule-override.json")
+}
+
+/// The override in force, if any.
+pub fn get_override() -> Option<Override> {
+  let content = fs::read_to_string(override_path()).ok()?;
+  let manual: Override = serde_json::from_str(&content).ok()?;
+  manual
+    .until
+    .is_none_or(|until| Local::now() < until)
+    .then_some(manual)
+}
+
+/// Sets the override, or goes back to the schedule with `None`.
+pub fn set_override(manual: Option<Override>) -> anyhow::Result<()> {
+  let path = override_path();
+  match manual {
+    Some(manual) => {
+      if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+      }
+      fs::write(path, serde_json::to_string_pretty(&manual)?)?;
+    }
+    None if path.exists() => fs::remove_file(path)?,
+    None => {}
+  }
+  Ok(())
+}
+
+/// Period the installation should be in now, the override first.
+pub fn current() -> Period {
+  match get_override() {
+    Some(manual) => manual.period,
+    None => show::get().schedule.period_at(Local::now().naive_local()),
+  }
+}
+
+/// Parses `22:00` (the next one), `2025-05-10` or `2025-05-10 22:00`.
+pub fn parse_until(value: &str) -> anyhow::Result<DateTime<Local>> {
+  let now = Local::now();
+  let naive = if let Ok(time) = NaiveTime::parse_from_str(value, "%H:%M") {
+    let today = now.date_naive().and_time(time);
+    if today > now.naive_local() {
+      today
+    } else {
+      today + TimeDelta::days(1)
+    }
+  } else if let Ok(day) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+    day.and_time(NaiveTime::MIN)
+  } else {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M")?
+  };
+  naive
+    .and_local_timezone(Local)
+    .earliest()
+    .ok_or_else(|| anyhow::anyhow!("Invalid local time {}", value))
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let value = String::deserialize(deserializer)?;
+  NaiveTime::parse_from_str(&value, "%H:%M").map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,267 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{self, ConfigParam};
+use crate::lights::driver::{DimmerDriver, Fixture};
+use crate::motors::MotorBus;
+use crate::robots;
+use crate::show;
+use crate::sparklings;
+use crate::utils::print_dry_run;
+
+const MIN_BUS_VOLTAGE_MV: u16 = 20000;
+const RELAY_PINS: [&str; 3] = ["s0", "s1", "s2"];
+const LIGHT_NAMES: [&str; 6] = ["A", "B", "C", "D", "E", "F"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+  Pass,
+  Fail,
+  Skip,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+  pub component: String,
+  pub status: Status,
+  pub detail: String,
+  /// Raw readings, for tooling
+  pub data: Value,
+}
+
+/// Hardware inventory, passed when no check failed.
+#[derive(Debug, Serialize)]
+pub struct Report {
+  pub passed: bool,
+  pub generated_at_ms: u64,
+  pub checks: Vec<Check>,
+}
+
+impl Report {
+  pub fn print(&self) {
+    println!("SELFTEST REPORT");
+    for check in &self.checks {
+      let status = match check.status {
+        Status::Pass => "PASS",
+        Status::Fail => "FAIL",
+        Status::Skip => "SKIP",
+      };
+      println!("[{}] {:<16} {}", status, check.component, check.detail);
+    }
+    println!("SELFTEST {}", if self.passed { "PASSED" } else { "FAILED" });
+  }
+
+  pub fn write(&self, path: &str) -> anyhow::Result<()> {
+    fs::write(path, serde_json::to_string_pretty(self)?)?;
+    Ok(())
+  }
+}
+
+/// Probes the motors, the dimmer and the Controllino without moving or
+/// switching anything.
+pub async fn run() -> Report {
+  crate::log_enter!("selftest.run", "");
+  let mut checks = tokio::task::spawn_blocking(|| {
+    let mut checks = check_motors();
+    checks.extend(check_lights());
+    checks
+  })
+  .await
+  .expect("Selftest panicked");
+  checks.push(check_controllino().await);
+  let report = Report {
+    passed: checks.iter().all(|check| check.status != Status::Fail),
+    generated_at_ms: SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_millis() as u64)
+      .unwrap_or(0),
+    checks,
+  };
+  crate::log_exit!("selftest.run", report.passed);
+  report
+}
+
+fn check_motors() -> Vec<Check> {
+  if config::get(ConfigParam::DRYRUN) {
+    print_dry_run("Invoked motor probe");
+    return robots::motor_addresses()
+      .iter()
+      .map(|(name, address)| Check {
+        component: format!("motor {} ({})", name, address),
+        status: Status::Skip,
+        detail: String::from("Dry run"),
+        data: Value::Null,
+      })
+      .collect();
+  }
+  let mut bus = match MotorBus::open() {
+    Ok(bus) => bus,
+    Err(e) => {
+      return vec![Check {
+        component: String::from("motor bus"),
+        status: Status::Fail,
+        detail: format!("Cannot open: {}", e),
+        data: Value::Null,
+      }]
+    }
+  };
+  robots::motor_addresses()
+    .iter()
+    .map(|(name, address)| {
+      let component = format!("motor {} ({})", name, address);
+      if !bus.probe(*address) {
+        return Check {
+          component,
+          status: Status::Fail,
+          detail: String::from("Not responding"),
+          data: Value::Null,
+        };
+      }
+      let voltage = bus.bus_voltage_mv(*address);
+      let status = bus.status(*address);
+      let mut problems = vec![];
+      match &voltage {
+        Ok(mv) if *mv < MIN_BUS_VOLTAGE_MV => {
+          problems.push(format!("low voltage {:.1} V", *mv as f64 / 1000.0))
+        }
+        Ok(_) => {}
+        Err(e) => problems.push(format!("voltage: {}", e)),
+      }
+      match &status {
+        Ok(flags) => {
+          if !flags.enabled {
+            problems.push(String::from("not enabled"));
+          }
+          if flags.stalled || flags.stall_protection {
+            problems.push(String::from("stalled"));
+          }
+        }
+        Err(e) => problems.push(format!("status: {}", e)),
+      }
+      let detail = if problems.is_empty() {
+        format!(
+          "{:.1} V, enabled",
+          *voltage.as_ref().unwrap() as f64 / 1000.0
+        )
+      } else {
+        problems.join(", ")
+      };
+      Check {
+        component,
+        status: if problems.is_empty() {
+          Status::Pass
+        } else {
+          Status::Fail
+        },
+        detail,
+        data: json!({
+          "voltage_mv": voltage.ok(),
+          "status": status.ok(),
+        }),
+      }
+    })
+    .collect()
+}
+
+fn check_lights() -> Vec<Check> {
+  let fixtures = &show::get().lights.fixtures;
+  let mut checks = vec![];
+  let mut dimmer_lights = vec![];
+  for name in LIGHT_NAMES {
+    match fixtures.get(name) {
+      None | Some(Fixture::Dimmer { .. }) => dimmer_lights.push(name),
+      Some(fixture) => checks.push(Check {
+        component: format!("light {}", name),
+        status: Status::Skip,
+        detail: String::from("DMX fixture, no feedback"),
+        data: json!({ "fixture": format!("{:?}", fixture) }),
+      }),
+    }
+  }
+  if dimmer_lights.is_empty() {
+    return checks;
+  }
+  let component = format!("dimmer ({})", dimmer_lights.join(","));
+  if config::get(ConfigParam::DRYRUN) {
+    print_dry_run("Invoked dimmer PING");
+    checks.insert(
+      0,
+      Check {
+        component,
+        status: Status::Skip,
+        detail: String::from("Dry run"),
+        data: Value::Null,
+      },
+    );
+    return checks;
+  }
+  let result = tokio::runtime::Handle::current()
+    .block_on(DimmerDriver::open())
+    .and_then(|mut dimmer| dimmer.ping());
+  let check = match result {
+    Ok(values) => Check {
+      component,
+      status: Status::Pass,
+      detail: format!("Acknowledged, outputs {:?}", values),
+      data: json!({ "values": values }),
+    },
+    Err(e) => Check {
+      component,
+      status: Status::Fail,
+      detail: format!("No acknowledgement: {}", e),
+      data: Value::Null,
+    },
+  };
+  checks.insert(0, check);
+  checks
+}
+
+async fn check_controllino() -> Check {
+  let component = String::from("controllino");
+  if config::get(ConfigParam::DRYRUN) {
+    print_dry_run("Invoked sparkling state query");
+    return Check {
+      component,
+      status: Status::Skip,
+      detail: String::from("Dry run"),
+      data: Value::Null,
+    };
+  }
+  match sparklings::query().await {
+    Ok(states) => {
+      let missing: Vec<&str> = RELAY_PINS
+        .iter()
+        .copied()
+        .filter(|pin| !states.contains_key(*pin))
+        .collect();
+      let detail = RELAY_PINS
+        .iter()
+        .map(|pin| match states.get(*pin) {
+          Some(true) => format!("{} on", pin),
+          Some(false) => format!("{} off", pin),
+          None => format!("{} missing", pin),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+      Check {
+        component,
+        status: if missing.is_empty() {
+          Status::Pass
+        } else {
+          Status::Fail
+        },
+        detail,
+        data: json!({ "states": states }),
+      }
+    }
+    Err(e) => Check {
+      component,
+      status: Status::Fail,
+      detail: format!("Not responding: {}", e),
+      data: Value::Null,
+    },
+  }
+}
@@ -10,7 +10,10 @@ pub fn send() -> std::io::Result<()> {
 
   loop {
     let Some(data) = RobotPositions::from_script() else {
-      eprintln!("Failed to get robot positions from Python script. Exiting.");
+      tracing::error!(
+        script = PYTHON_SCRIPT_PATH,
+        "Cannot get robot positions, exiting"
+      );
       return Err(std::io::Error::new(
         std::io::ErrorKind::Other,
         "Failed to get robot positions from Python script",
@@ -42,11 +45,12 @@ impl RobotPositions {
       .ok()?;
 
     if !output.status.success() {
-      eprintln!(
-        "Python script failed with exit code: {:?}",
-        output.status.code()
+      tracing::error!(
+        script = PYTHON_SCRIPT_PATH,
+        code = ?output.status.code(),
+        stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+        "Position script failed"
       );
-      eprintln!("Stderr: {}", String::from_utf8_lossy(&output.stderr));
       return None;
     }
 
@@ -54,7 +58,7 @@ impl RobotPositions {
 
     let parts: Vec<&str> = raw.trim().split(';').collect();
     if parts.len() != 4 {
-      eprintln!("Unexpected output format: {:?}", parts);
+      tracing::error!(?parts, "Unexpected position script output");
       return None;
     }
 
@@ -67,7 +71,7 @@ impl RobotPositions {
         r3: vals[3],
       }),
       Err(e) => {
-        eprintln!("Error parsing values: {:?}", e);
+        tracing::error!(error = %e, "Cannot parse robot positions");
         None
       }
     }
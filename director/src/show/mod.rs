@@ -0,0 +1,74 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+
+use crate::estop::EstopConfig;
+use crate::fanout::FanoutConfig;
+use crate::inputs::InputsConfig;
+use crate::journal::JournalConfig;
+use crate::lights::LightsConfig;
+use crate::metrics::MetricsConfig;
+use crate::params::Params;
+use crate::presence::PresenceConfig;
+use crate::robots::choreography::Choreography;
+use crate::schedule::Schedule;
+use crate::sparklings::SparklingLimits;
+
+/// Show file: a JSON document passed with `--show <path>` holding the
+/// parameters of a run.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Show {
+  pub seed: Option<u64>,
+  /// Pacing of the phases, changeable live with `director params`
+  pub params: Params,
+  pub choreography: Choreography,
+  pub lights: LightsConfig,
+  /// Safety limits by sparkling name
+  pub sparklings: HashMap<String, SparklingLimits>,
+  pub fanout: FanoutConfig,
+  pub self_test: SelfTest,
+  pub metrics: MetricsConfig,
+  pub journal: JournalConfig,
+  pub schedule: Schedule,
+  pub estop: EstopConfig,
+  /// Controllino inputs by pin
+  pub inputs: InputsConfig,
+  pub presence: PresenceConfig,
+}
+
+/// Startup blink of the lights and sparklings, `hold_ms` on then off.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct SelfTest {
+  pub enabled: bool,
+  pub hold_ms: u64,
+}
+
+impl Default for SelfTest {
+  fn default() -> Self {
+    SelfTest {
+      enabled: true,
+      hold_ms: 2000,
+    }
+  }
+}
+
+pub static SHOW: OnceLock<Show> = OnceLock::new();
+
+pub fn load(path: &str) -> anyhow::Result<Show> {
+  let content = fs::read_to_string(path)?;
+  let show: Show = serde_json::from_str(&content)?;
+  show.params.validate()?;
+  show.choreography.validate()?;
+  show.lights.validate()?;
+  show.schedule.validate()?;
+  show.inputs.validate()?;
+  show.presence.validate()?;
+  Ok(show)
+}
+
+pub fn get() -> &'static Show {
+  SHOW.get_or_init(Show::default)
+}
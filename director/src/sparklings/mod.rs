@@ -1,65 +1,184 @@
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
 use crate::config::{self, ConfigParam};
+use crate::estop;
+use crate::fanout;
+use crate::journal::{self, Origin, Outcome};
+use crate::metrics;
+use crate::random;
+use crate::show;
 use crate::utils::{self, print_dry_run};
 
-const SPARKLING_SERVICE_IP: &str = "192.168.125.3";
+pub mod relay;
+use relay::{RelayController, RelayStates};
+
+pub const SPARKLING_SERVICE_IP: &str = "192.168.125.3";
+
+const WATCHDOG_INTERVAL_MS: u64 = 500;
+const HEARTBEAT_INTERVAL_MS: u64 = 2000;
+// Auto-off delay armed on the Controllino by every heartbeat
+const HEARTBEAT_TTL_MS: u64 = 5000;
+
+/// Safety limits of a sparkling, by name in the `sparklings` section of the
+/// show file: `{ "A": { "max_on_ms": 15000, "cool_down_ms": 20000 } }`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct SparklingLimits {
+  /// Longest the relay may stay on, it is forced off after
+  pub max_on_ms: u64,
+  /// Shortest time off before the relay may be turned on again
+  pub cool_down_ms: u64,
+}
+
+impl Default for SparklingLimits {
+  fn default() -> Self {
+    SparklingLimits {
+      max_on_ms: 1000 * 15,
+      cool_down_ms: 1000 * 20,
+    }
+  }
+}
 
 pub struct SparklingManager {
-  pub sparkling_a: Sparkling,
-  pub sparkling_b: Sparkling,
-  pub sparkling_c: Sparkling,
+  pub sparkling_a: Arc<Sparkling>,
+  pub sparkling_b: Arc<Sparkling>,
+  pub sparkling_c: Arc<Sparkling>,
+  rng: Mutex<StdRng>,
 }
 
 impl SparklingManager {
   pub async fn new() -> Self {
+    let relays = Arc::new(RelayController::new(SPARKLING_SERVICE_IP));
     let mut sparkiling_manager: SparklingManager = SparklingManager {
-      sparkling_a: Sparkling::new(0, "A", "s0"),
-      sparkling_b: Sparkling::new(1, "B", "s1"),
-      sparkling_c: Sparkling::new(2, "C", "s2"),
+      sparkling_a: Arc::new(Sparkling::new(0, "A", "s0", &relays, true)),
+      sparkling_b: Arc::new(Sparkling::new(1, "B", "s1", &relays, true)),
+      sparkling_c: Arc::new(Sparkling::new(2, "C", "s2", &relays, true)),
+      rng: Mutex::new(random::fork("SparklingManager")),
     };
-    sparkiling_manager.all_turn_on().await;
-    utils::sleep(5000, "SparklingManager new").await;
-    sparkiling_manager.all_turn_off().await;
+    sparkiling_manager.read_states(&relays).await;
+    let sparklings = sparkiling_manager.sparklings().map(Arc::clone);
+    tokio::spawn(journal::scope(Origin::Watchdog, watchdog(sparklings)));
+    let self_test = show::get().self_test;
+    if self_test.enabled {
+      sparkiling_manager.all_turn_on().await;
+      utils::sleep(self_test.hold_ms, "SparklingManager new").await;
+      sparkiling_manager.all_turn_off().await;
+    }
     return sparkiling_manager;
   }
+  /// Records the relay states the Controllino reports, e.g. after a restart
+  /// of the director with the relays left on.
+  async fn read_states(&self, relays: &RelayController) {
+    if config::get(ConfigParam::DRYRUN) {
+      print_dry_run("Invoked sparkling state query");
+      return;
+    }
+    match relays.query().await {
+      Ok(states) => {
+        for sparkling in self.sparklings() {
+          sparkling.record(&states);
+          if sparkling.is_on() == Some(true) {
+            // Left on by a previous run, the watchdog takes it from here
+            sparkling.timing.lock().unwrap().on_since = Some(Instant::now());
+          }
+        }
+      }
+      Err(e) => tracing::error!(error = %e, "Cannot read sparkling states"),
+    }
+  }
+
+  pub fn sparklings(&self) -> [&Arc<Sparkling>; 3] {
+    [&self.sparkling_a, &self.sparkling_b, &self.sparkling_c]
+  }
+
   pub async fn run_sparkling(&self) {
-    self.sparkling_a.run_sparkling().await;
-    utils::sleep(1000, "SparklingManager run_sparkling").await;
-    self.sparkling_b.run_sparkling().await;
-    utils::sleep(1000, "SparklingManager run_sparkling").await;
-    self.sparkling_c.run_sparkling().await;
+    self
+      .for_each(|sparkling| async move {
+        sparkling.run_sparkling().await;
+      })
+      .await;
   }
   pub async fn all_turn_on(&mut self) {
     crate::log_enter!("sparkling.all_turn_on", "");
-    self.sparkling_a.turn_on().await;
-    utils::sleep(2000, "SparklingManager all_turn_on").await;
-    self.sparkling_b.turn_on().await;
-    utils::sleep(2000, "SparklingManager all_turn_on").await;
-    self.sparkling_c.turn_on().await;
+    self
+      .for_each(|sparkling| async move {
+        sparkling.turn_on().await;
+      })
+      .await;
     crate::log_exit!("sparkling.all_turn_on", "");
   }
   pub async fn all_turn_off(&mut self) {
     crate::log_enter!("sparkling.all_turn_off", "");
-    self.sparkling_a.turn_off().await;
-    utils::sleep(2000, "SparklingManager all_turn_off").await;
-    self.sparkling_b.turn_off().await;
-    utils::sleep(2000, "SparklingManager all_turn_off").await;
-    self.sparkling_c.turn_off().await;
+    self
+      .for_each(|sparkling| async move {
+        sparkling.turn_off().await;
+      })
+      .await;
     crate::log_exit!("sparkling.all_turn_off", "");
   }
+  /// Runs `op` on every sparkling following the sparklings stagger of the
+  /// show.
+  async fn for_each<F, Fut>(&self, op: F)
+  where
+    F: Fn(Arc<Sparkling>) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+  {
+    let sparklings = self.sparklings().map(Arc::clone).to_vec();
+    fanout::run(show::get().fanout.sparklings, sparklings, &self.rng, op).await;
+  }
 }
 
 pub struct Sparkling {
   pub id: u8,
   pub name: &'static str,
   pub service_name: &'static str,
+  relays: Arc<RelayController>,
+  // Last state confirmed by the Controllino, `None` until known
+  state: Mutex<Option<bool>>,
+  limits: SparklingLimits,
+  // Kept on by heartbeats from the watchdog, otherwise the Controllino
+  // turns the relay off after `max_on_ms` by itself
+  heartbeat: bool,
+  timing: Mutex<Timing>,
+  // Held while a relay request is in flight, so an OFF is never overtaken
+  // by an ON sent before it
+  switching: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct Timing {
+  on_since: Option<Instant>,
+  off_since: Option<Instant>,
+  heartbeat_at: Option<Instant>,
 }
 
 impl Sparkling {
-  pub fn new(id: u8, name: &'static str, service_name: &'static str) -> Self {
+  pub fn new(
+    id: u8,
+    name: &'static str,
+    service_name: &'static str,
+    relays: &Arc<RelayController>,
+    heartbeat: bool,
+  ) -> Self {
+    let limits = show::get()
+      .sparklings
+      .get(name)
+      .copied()
+      .unwrap_or_default();
     let light: Sparkling = Sparkling {
       id,
       name,
       service_name,
+      relays: Arc::clone(relays),
+      state: Mutex::new(None),
+      limits,
+      heartbeat,
+      timing: Mutex::new(Timing::default()),
+      switching: tokio::sync::Mutex::new(()),
     };
     light.print();
     return light;
@@ -71,50 +190,190 @@ impl Sparkling {
   }
   pub async fn turn_on(&self) {
     crate::log_enter!("sparkling.turn_on", self.name);
+    if let Some(left_ms) = self.cool_down_left_ms() {
+      tracing::warn!(
+        sparkling = self.name,
+        left_ms,
+        "Cooling down, not turning ON"
+      );
+      crate::log_exit!("sparkling.turn_on", self.name);
+      return;
+    }
+    if estop::latched().is_some() {
+      tracing::warn!(sparkling = self.name, "Emergency stop, not turning ON");
+      crate::log_exit!("sparkling.turn_on", self.name);
+      return;
+    }
+    {
+      let mut timing = self.timing.lock().unwrap();
+      timing.on_since.get_or_insert_with(Instant::now);
+      timing.heartbeat_at = Some(Instant::now());
+    }
     if config::get(ConfigParam::DRYRUN) {
       print_dry_run(format!("SPARKLING [{}] turned ON", self.name).as_str());
+      self.journal(true, Outcome::Ok, None);
+      self.set_state(Some(true));
       crate::log_exit!("sparkling.turn_on", self.name);
       return;
     }
-    invoke_service(self.service_name, "on").await;
+    self.switch(true).await;
     crate::log_exit!("sparkling.turn_on", self.name);
   }
   pub async fn turn_off(&self) {
     crate::log_enter!("sparkling.turn_off", self.name);
+    {
+      let mut timing = self.timing.lock().unwrap();
+      if let Some(on_since) = timing.on_since.take() {
+        timing.off_since = Some(Instant::now());
+        metrics::add(
+          &metrics::SPARKLING_ON_SECONDS,
+          &[("sparkling", self.name)],
+          on_since.elapsed().as_secs_f64(),
+        );
+      }
+    }
     if config::get(ConfigParam::DRYRUN) {
       print_dry_run(format!("SPARKLING [{}] turned OFF", self.name).as_str());
+      self.journal(false, Outcome::Ok, None);
+      self.set_state(Some(false));
       crate::log_exit!("sparkling.turn_off", self.name);
       return;
     }
-    invoke_service(self.service_name, "off").await;
+    self.switch(false).await;
     crate::log_exit!("sparkling.turn_off", self.name);
   }
-  fn print(&self) {
-    println!("SPARKLING {} {}", self.id, self.name);
+  /// Whether the relay is on, as last confirmed by the Controllino.
+  pub fn is_on(&self) -> Option<bool> {
+    *self.state.lock().unwrap()
+  }
+
+  fn cool_down_left_ms(&self) -> Option<u64> {
+    let timing = self.timing.lock().unwrap();
+    if timing.on_since.is_some() {
+      return None;
+    }
+    let elapsed = timing.off_since?.elapsed().as_millis() as u64;
+    (elapsed < self.limits.cool_down_ms)
+      .then(|| self.limits.cool_down_ms - elapsed)
+  }
+
+  async fn switch(&self, on: bool) {
+    let _switching = self.switching.lock().await;
+    // Turned off while waiting, e.g. a heartbeat racing `turn_off`
+    if on && self.timing.lock().unwrap().on_since.is_none() {
+      return;
+    }
+    if on && estop::latched().is_some() {
+      return;
+    }
+    let ttl_ms = if self.heartbeat {
+      HEARTBEAT_TTL_MS
+    } else {
+      self.limits.max_on_ms
+    };
+    match self.relays.set(self.service_name, on, ttl_ms).await {
+      Ok(states) => {
+        self.journal(on, Outcome::Ok, None);
+        self.record(&states);
+      }
+      Err(e) => {
+        self.journal(on, Outcome::Failed, Some(e.to_string()));
+        tracing::error!(sparkling = self.name, error = %e, "Relay failed");
+        // Whatever the relay did, it is not known anymore
+        *self.state.lock().unwrap() = None;
+      }
+    }
   }
-}
 
-async fn invoke_service(path: &str, state: &str) {
-  let url = format!("http://{}/{path}?state={state}", SPARKLING_SERVICE_IP);
-  let response = reqwest::get(&url)
-    .await
-    .expect("SPARKLING SERVICE INVOCATION FAILED");
+  fn journal(&self, on: bool, result: Outcome, error: Option<String>) {
+    let (command, params) = match (on, self.heartbeat) {
+      (true, true) => ("on", json!({ "ttl_ms": HEARTBEAT_TTL_MS })),
+      (true, false) => ("on", json!({ "ttl_ms": self.limits.max_on_ms })),
+      (false, _) => ("off", json!({})),
+    };
+    journal::record(
+      &format!("sparkling {}", self.name),
+      command,
+      params,
+      result,
+      error,
+    );
+  }
 
-  if !response.status().is_success() {
-    panic!("Request failed with status: {}", response.status());
+  fn record(&self, states: &RelayStates) {
+    self.set_state(states.get(self.service_name).copied());
   }
 
-  let body = response.text().await.expect("Failed to read response body");
-  println!("Sparkling service returned: {}", body);
+  fn set_state(&self, state: Option<bool>) {
+    *self.state.lock().unwrap() = state;
+    if let Some(on) = state {
+      metrics::set(
+        &metrics::SPARKLING_ON,
+        &[("sparkling", self.name)],
+        on as u8 as f64,
+      );
+    }
+  }
+
+  fn print(&self) {
+    tracing::info!(id = self.id, sparkling = self.name, "SPARKLING");
+  }
 }
 
+/// A single sparkling for one-off commands, without watchdog: the
+/// Controllino turns it off after its maximum on-time.
 pub fn create(id: &str) -> Sparkling {
+  let relays = Arc::new(RelayController::new(SPARKLING_SERVICE_IP));
   match id {
-    "1" => Sparkling::new(0, "A", "s0"),
-    "2" => Sparkling::new(1, "B", "s1"),
-    "3" => Sparkling::new(2, "C", "s2"),
+    "1" => Sparkling::new(0, "A", "s0", &relays, false),
+    "2" => Sparkling::new(1, "B", "s1", &relays, false),
+    "3" => Sparkling::new(2, "C", "s2", &relays, false),
     _ => {
       panic!("Invalid Light ID. Possible value [1-3]");
     }
   }
 }
+
+/// States of all relays as reported by the Controllino.
+pub async fn query() -> anyhow::Result<RelayStates> {
+  if config::get(ConfigParam::DRYRUN) {
+    print_dry_run("Invoked sparkling state query");
+    return Ok(RelayStates::new());
+  }
+  RelayController::new(SPARKLING_SERVICE_IP).query().await
+}
+
+/// Forces off the sparklings on for longer than their maximum on-time, and
+/// sends the heartbeats keeping the others on.
+async fn watchdog(sparklings: [Arc<Sparkling>; 3]) {
+  tracing::info!("Starting Sparkling watchdog...");
+  loop {
+    for sparkling in &sparklings {
+      let (on_for_ms, heartbeat_due) = {
+        let timing = sparkling.timing.lock().unwrap();
+        let Some(on_since) = timing.on_since else {
+          continue;
+        };
+        let heartbeat_due = timing.heartbeat_at.is_none_or(|at| {
+          at.elapsed().as_millis() as u64 >= HEARTBEAT_INTERVAL_MS
+        });
+        (on_since.elapsed().as_millis() as u64, heartbeat_due)
+      };
+      if on_for_ms >= sparkling.limits.max_on_ms {
+        tracing::warn!(
+          sparkling = sparkling.name,
+          on_for_ms,
+          "Over its maximum on-time, forcing OFF"
+        );
+        sparkling.turn_off().await;
+      } else if heartbeat_due
+        && !config::get(ConfigParam::DRYRUN)
+        && estop::latched().is_none()
+      {
+        sparkling.timing.lock().unwrap().heartbeat_at = Some(Instant::now());
+        sparkling.switch(true).await;
+      }
+    }
+    utils::sleep_silent(WATCHDOG_INTERVAL_MS).await;
+  }
+}
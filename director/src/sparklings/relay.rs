@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::metrics;
+use crate::utils;
+
+const RELAY_TIMEOUT_MS: u64 = 2000;
+const RELAY_ATTEMPTS: u32 = 3;
+const RELAY_RETRY_DELAY_MS: u64 = 500;
+
+/// State of every relay pin by name (`s0`, `s1`, ...), as reported by the
+/// Controllino.
+pub type RelayStates = HashMap<String, bool>;
+
+/// HTTP client of the Controllino relays. Every request is answered with the
+/// state of all pins, e.g. `{"s0": true, "s1": false, "s2": false}`, which is
+/// used to confirm each change.
+pub struct RelayController {
+  client: reqwest::Client,
+  base_url: String,
+}
+
+impl RelayController {
+  pub fn new(ip: &str) -> Self {
+    let client = reqwest::Client::builder()
+      .timeout(Duration::from_millis(RELAY_TIMEOUT_MS))
+      .build()
+      .expect("Cannot build relay HTTP client");
+    RelayController {
+      client,
+      base_url: format!("http://{}", ip),
+    }
+  }
+
+  /// Current state of all pins.
+  pub async fn query(&self) -> anyhow::Result<RelayStates> {
+    self.request("/").await
+  }
+
+  /// Debounced level of the digital inputs (`i0` to `i3`), true when high.
+  pub async fn inputs(&self) -> anyhow::Result<RelayStates> {
+    self.request("/inputs").await
+  }
+
+  /// Same as `inputs` in a single attempt bounded by `timeout_ms`, for
+  /// polling: a missed poll is not worth waiting for.
+  pub async fn poll_inputs(
+    &self,
+    timeout_ms: u64,
+  ) -> anyhow::Result<RelayStates> {
+    let url = format!("{}/inputs", self.base_url);
+    let request = self
+      .client
+      .get(&url)
+      .timeout(Duration::from_millis(timeout_ms));
+    let result = self.send(request).await;
+    if result.is_err() {
+      metrics::failure("http");
+    }
+    result
+  }
+
+  /// Switches `pin` and checks the Controllino reports it in the new state.
+  /// A pin turned on goes off by itself after `ttl_ms`, unless it is turned
+  /// on again before.
+  pub async fn set(
+    &self,
+    pin: &str,
+    on: bool,
+    ttl_ms: u64,
+  ) -> anyhow::Result<RelayStates> {
+    let state = if on { "on" } else { "off" };
+    let path = if on {
+      format!("/{}?state={}&ttl={}", pin, state, ttl_ms)
+    } else {
+      format!("/{}?state={}", pin, state)
+    };
+    let states = self.request(&path).await?;
+    match states.get(pin) {
+      Some(reported) if *reported == on => Ok(states),
+      Some(_) => anyhow::bail!("Relay {} did not switch {}", pin, state),
+      None => anyhow::bail!("Relay {} missing from {:?}", pin, states),
+    }
+  }
+
+  async fn request(&self, path: &str) -> anyhow::Result<RelayStates> {
+    let url = format!("{}{}", self.base_url, path);
+    let mut attempt = 1;
+    loop {
+      match self.get(&url).await {
+        Ok(states) => return Ok(states),
+        Err(e) if attempt < RELAY_ATTEMPTS => {
+          metrics::failure("http");
+          tracing::warn!(
+            url,
+            attempt,
+            attempts = RELAY_ATTEMPTS,
+            error = %e,
+            "Relay request failed"
+          );
+          attempt += 1;
+          utils::sleep_silent(RELAY_RETRY_DELAY_MS).await;
+        }
+        Err(e) => {
+          metrics::failure("http");
+          return Err(e);
+        }
+      }
+    }
+  }
+
+  async fn get(&self, url: &str) -> anyhow::Result<RelayStates> {
+    self.send(self.client.get(url)).await
+  }
+
+  async fn send(
+    &self,
+    request: reqwest::RequestBuilder,
+  ) -> anyhow::Result<RelayStates> {
+    let response = request.send().await?;
+    if !response.status().is_success() {
+      anyhow::bail!("Request failed with status: {}", response.status());
+    }
+    let body = response.text().await?;
+    tracing::debug!(response = body.trim(), "Sparkling service returned");
+    let states: RelayStates = serde_json::from_str(body.trim())?;
+    Ok(states)
+  }
+}
@@ -1,9 +1,13 @@
-use serialport::TTYPort;
+use serialport::{ClearBuffer, SerialPort, TTYPort};
 use std::env;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::process::Command;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAX_HTTP_REQUEST_BYTES: usize = 4096;
 
 pub enum ScriptName {
   RobotGetPosition,
@@ -21,8 +25,18 @@ impl ScriptName {
   }
 }
 
-pub trait SerialDevice {
+pub trait SerialDevice: Send {
   fn send_message(&mut self, message: &str) -> anyhow::Result<()>;
+  /// Next line written by the device, without the line ending.
+  fn read_line(&mut self) -> anyhow::Result<String>;
+  /// How long `read_line` waits for each byte.
+  fn set_timeout(&mut self, _timeout: Duration) -> anyhow::Result<()> {
+    Ok(())
+  }
+  /// Drops what the device wrote and was not read, e.g. a late answer.
+  fn clear_input(&mut self) -> anyhow::Result<()> {
+    Ok(())
+  }
 }
 
 pub struct RealSerialDevice {
@@ -51,9 +65,11 @@ impl RealSerialDevice {
 impl SerialDevice for RealSerialDevice {
   fn send_message(&mut self, message: &str) -> anyhow::Result<()> {
     let msg = format!("{}\r\n", message); // <-- use CRLF like Arduino IDE does
-    println!(
-      "Sending message {} to serial [{}] with baud rate {}...",
-      msg, self.port_name, self.baud_rate
+    tracing::debug!(
+      port = self.port_name,
+      baud = self.baud_rate,
+      "Sending message {} to serial",
+      message
     );
     // Write slowly, byte by byte (imitates Serial Monitor pacing)
     for byte in msg.bytes() {
@@ -63,22 +79,49 @@ impl SerialDevice for RealSerialDevice {
     self.port.flush()?; // Ensure all data is sent
     Ok(())
   }
+
+  fn read_line(&mut self) -> anyhow::Result<String> {
+    let mut line = vec![];
+    let mut byte = [0u8; 1];
+    loop {
+      self.port.read_exact(&mut byte)?;
+      if byte[0] == b'\n' {
+        break;
+      }
+      line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim().to_string())
+  }
+
+  fn set_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+    self.port.set_timeout(timeout)?;
+    Ok(())
+  }
+
+  fn clear_input(&mut self) -> anyhow::Result<()> {
+    self.port.clear(ClearBuffer::Input)?;
+    Ok(())
+  }
 }
 
 pub struct MockSerialDevice {}
 
 impl MockSerialDevice {
   pub fn new(port_name: &str, baud_rate: u32) -> anyhow::Result<Self> {
-    println!("Initialized MockSerialDevice {} {}", port_name, baud_rate);
+    tracing::info!("Initialized MockSerialDevice {} {}", port_name, baud_rate);
     Ok(MockSerialDevice {})
   }
 }
 
 impl SerialDevice for MockSerialDevice {
   fn send_message(&mut self, message: &str) -> anyhow::Result<()> {
-    println!("[MOCK] Message sent to serial port: {}", message);
+    tracing::info!("[MOCK] Message sent to serial port: {}", message);
     Ok(())
   }
+
+  fn read_line(&mut self) -> anyhow::Result<String> {
+    anyhow::bail!("[MOCK] Nothing to read from serial port")
+  }
 }
 
 // pub fn sleep(ms: u64) {
@@ -86,11 +129,7 @@ impl SerialDevice for MockSerialDevice {
 //   sleep_silent(ms);
 // }
 pub async fn sleep(milliseconds: u64, name: &str) {
-  println!(
-    "[{}] Sleeping for {} milliseconds...",
-    name,
-    milliseconds.to_string()
-  );
+  tracing::debug!("[{}] Sleeping for {} milliseconds...", name, milliseconds);
   tokio::time::sleep(std::time::Duration::from_millis(milliseconds)).await;
 }
 
@@ -101,6 +140,47 @@ pub async fn sleep_silent(milliseconds: u64) {
   tokio::time::sleep(std::time::Duration::from_millis(milliseconds)).await;
 }
 
+/// Method and path of an HTTP request, e.g. `("POST", "/estop")`.
+pub async fn read_http_request(
+  stream: &mut TcpStream,
+) -> anyhow::Result<(String, String)> {
+  let mut request = vec![];
+  let mut buf = [0; 512];
+  while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+    let read = stream.read(&mut buf).await?;
+    if read == 0 || request.len() + read > MAX_HTTP_REQUEST_BYTES {
+      break;
+    }
+    request.extend_from_slice(&buf[..read]);
+  }
+  let request = String::from_utf8_lossy(&request);
+  let mut line = request.lines().next().unwrap_or("").split_whitespace();
+  Ok((
+    line.next().unwrap_or("").to_string(),
+    line.next().unwrap_or("").to_string(),
+  ))
+}
+
+/// Answers an HTTP request with a text `body` and closes the connection.
+pub async fn write_http_response(
+  stream: &mut TcpStream,
+  status: &str,
+  content_type: &str,
+  body: &str,
+) -> anyhow::Result<()> {
+  let response = format!(
+    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+     Connection: close\r\n\r\n{}",
+    status,
+    content_type,
+    body.len(),
+    body
+  );
+  stream.write_all(response.as_bytes()).await?;
+  stream.shutdown().await?;
+  Ok(())
+}
+
 pub fn get_home_dir() -> PathBuf {
   if cfg!(target_os = "windows") {
     env::var("USERPROFILE")
@@ -132,9 +212,9 @@ pub fn invoke_script(
   full_args.extend_from_slice(args);
 
   // Debug print: show full path and arguments
-  println!("Invoking script: {}", script_path.display());
-  println!("With arguments: {:?}", args);
+  tracing::debug!(script = %script_path.display(), ?args, "Invoking script");
 
+  crate::motors::release_bus();
   let output = Command::new("python")
     .args(&full_args)
     .output()
@@ -142,11 +222,12 @@ pub fn invoke_script(
     .unwrap();
 
   if !output.status.success() {
-    eprintln!(
-      "Python script failed with exit code: {:?}",
-      output.status.code()
+    crate::metrics::failure("script");
+    tracing::error!(
+      code = ?output.status.code(),
+      stderr = %String::from_utf8_lossy(&output.stderr),
+      "Python script failed"
     );
-    eprintln!("Stderr: {}", String::from_utf8_lossy(&output.stderr));
     panic!("Script failed")
   }
 
@@ -154,7 +235,7 @@ pub fn invoke_script(
 }
 
 pub fn print_dry_run(msg: &str) {
-  println!("[DRY RUN]: {}", msg);
+  tracing::info!(dry_run = true, "[DRY RUN]: {}", msg);
 }
 
 #[macro_export]
@@ -163,9 +244,7 @@ macro_rules! log_enter {
         let args = vec![
             $( format!("{}", $val) ),*
         ];
-        if crate::config::get(crate::config::ConfigParam::VERBOSE) == true {
-          println!("→ Entering {}({})", $name, args.join(", "));
-        }
+        tracing::debug!("→ Entering {}({})", $name, args.join(", "));
     }};
 }
 
@@ -175,8 +254,6 @@ macro_rules! log_exit {
         let args = vec![
             $( format!("{}", $val) ),*
         ];
-        if crate::config::get(crate::config::ConfigParam::VERBOSE) == true {
-          println!("← Exiting {}({})", $name, args.join(", "));
-        }
+        tracing::debug!("← Exiting {}({})", $name, args.join(", "));
     }};
 }
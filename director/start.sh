@@ -17,11 +17,13 @@ trap 'echo -e "\nStopping..."; jobs -p | xargs -r kill; exit' INT TERM
 
 # Function to handle sparkling (relay control) - runs in background
 _sparkling_loop() {
+  # The Controllino turns the relays off by itself if this script dies
+  RELAY_TTL_MS=$(( (SPARKLING_TIME + 5) * 1000 ))
   while true; do
     echo "[$(date)] Turning ON s0, s1 and s2..."
-    curl "http://192.168.125.3/s0?state=on"
-    curl "http://192.168.125.3/s1?state=on"
-    curl "http://192.168.125.3/s2?state=on"
+    curl "http://192.168.125.3/s0?state=on&ttl=$RELAY_TTL_MS"
+    curl "http://192.168.125.3/s1?state=on&ttl=$RELAY_TTL_MS"
+    curl "http://192.168.125.3/s2?state=on&ttl=$RELAY_TTL_MS"
 
     echo "[$(date)] Waiting $SPARKLING_TIME seconds with relays ON..."
     sleep $SPARKLING_TIME
@@ -85,8 +85,43 @@ void loop() {
       updateAllDACOutputs();
 
       Serial.println("All DAC values updated.");
+    } else if (input.startsWith("SET")) {
+      // SET <v1> <v2> <v3> <v4> <v5> <v6>: all channels in one frame
+      int values[6];
+      int start = 4;
+      for (int i = 0; i < 6; i++) {
+        int end = input.indexOf(' ', start);
+        if (end == -1) end = input.length();
+        if (start >= end) {
+          Serial.println("Invalid SET. Use: SET <v1> <v2> <v3> <v4> <v5> <v6>");
+          return;
+        }
+        values[i] = constrain(input.substring(start, end).toInt(), 0, 10000);
+        start = end + 1;
+      }
+
+      Serial.print("Parsed values:");
+      for (int i = 0; i < 6; i++) {
+        dac_values[i] = values[i];
+        Serial.print(" ");
+        Serial.print(values[i]);
+      }
+      Serial.println();
+
+      updateAllDACOutputs();
+
+      Serial.println("All DAC values updated.");
+    } else if (input.startsWith("PING")) {
+      // Liveness check, reports the current values without changing them
+      Serial.print("Values:");
+      for (int i = 0; i < 6; i++) {
+        Serial.print(" ");
+        Serial.print(dac_values[i]);
+      }
+      Serial.println();
+      Serial.println("PONG");
     } else {
-      Serial.println("Invalid command. Use: DIM <1–6> <0–10000>");
+      Serial.println("Invalid command. Use: DIM <1–6> <0–10000>, SET <v1> ... <v6> or PING");
     }
   }
 }
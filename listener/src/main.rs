@@ -17,14 +17,14 @@ fn main() -> std::io::Result<()> {
     match decode_udp(&buf[..size]) {
       Ok((_, packet)) => match packet {
         rosc::OscPacket::Message(msg) => {
-          if msg.addr == "/robots" {
+          if msg.addr == "/robots" || msg.addr == "/lights" {
             println!("Received from {}: {:?}", addr, msg);
           }
         }
         rosc::OscPacket::Bundle(bundle) => {
           for p in bundle.content {
             if let rosc::OscPacket::Message(msg) = p {
-              if msg.addr == "/robots" {
+              if msg.addr == "/robots" || msg.addr == "/lights" {
                 println!("Received from {}: {:?}", addr, msg);
               }
             }